@@ -0,0 +1,8 @@
+# Show the packages and nesting depth of the current Nix shell.
+function fish_right_prompt
+    if set -q NIX_YOUR_SHELL_DEPTH
+        set_color blue
+        echo -n "[nix:$NIX_YOUR_SHELL_DEPTH]" (string trim -- "$NIX_YOUR_SHELL_PACKAGES $NIX_YOUR_SHELL_INSTALLABLES")
+        set_color normal
+    end
+end
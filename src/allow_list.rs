@@ -0,0 +1,119 @@
+use std::collections::BTreeMap;
+
+use camino::Utf8Path;
+use camino::Utf8PathBuf;
+use miette::Context;
+use miette::IntoDiagnostic;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+
+use crate::dirs;
+
+/// The name of the file in the data directory listing the allowed project configuration files.
+const ALLOW_LIST_FILE: &str = "allow.json";
+
+/// Project configuration files the user has allowed, like `direnv allow`.
+///
+/// Each file is allowed with the hash of its contents, so that changes have to be allowed again.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct AllowList {
+    /// SHA-256 hashes of the allowed files' contents, keyed by canonicalized path.
+    allowed: BTreeMap<Utf8PathBuf, String>,
+}
+
+impl AllowList {
+    /// Load the allow list, or an empty allow list if none exists yet.
+    pub fn load() -> miette::Result<Self> {
+        let path = Self::path()?;
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let contents = std::fs::read_to_string(&path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to read {path}"))?;
+        serde_json::from_str(&contents)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to parse {path}"))
+    }
+
+    /// Is the file at `path` allowed with the given contents?
+    ///
+    /// The caller reads the file once and uses the same `contents` afterwards, so that the file
+    /// can't change between being checked and being used.
+    pub fn is_allowed(&self, path: &Utf8Path, contents: &[u8]) -> miette::Result<bool> {
+        let path = canonicalize(path)?;
+        Ok(self
+            .allowed
+            .get(&path)
+            .is_some_and(|allowed| *allowed == hash(contents)))
+    }
+
+    /// Allow the file at `path` with the given contents.
+    pub fn allow(&mut self, path: &Utf8Path, contents: &[u8]) -> miette::Result<()> {
+        let path = canonicalize(path)?;
+        self.allowed.insert(path, hash(contents));
+        Ok(())
+    }
+
+    /// Stop allowing the file at `path`.
+    ///
+    /// Returns `false` if the file wasn't allowed.
+    pub fn deny(&mut self, path: &Utf8Path) -> bool {
+        // The file may have been deleted already.
+        let path = path.canonicalize_utf8().unwrap_or_else(|_| path.to_owned());
+        self.allowed.remove(&path).is_some()
+    }
+
+    /// Write the allow list to disk.
+    pub fn save(&self) -> miette::Result<()> {
+        let path = Self::path()?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to create {parent}"))?;
+        }
+        let contents = serde_json::to_string_pretty(self).into_diagnostic()?;
+        std::fs::write(&path, contents)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to write {path}"))
+    }
+
+    fn path() -> miette::Result<Utf8PathBuf> {
+        Ok(dirs::data_dir()?.join(ALLOW_LIST_FILE))
+    }
+}
+
+fn canonicalize(path: &Utf8Path) -> miette::Result<Utf8PathBuf> {
+    path.canonicalize_utf8()
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to resolve path to {path}"))
+}
+
+/// The SHA-256 hash of a file's contents, in hex.
+fn hash(contents: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(contents))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allow() {
+        let path = Utf8PathBuf::from("Cargo.toml");
+        let mut allow_list = AllowList::default();
+        assert!(!allow_list.is_allowed(&path, b"nix-flags = []").unwrap());
+
+        allow_list.allow(&path, b"nix-flags = []").unwrap();
+        assert!(allow_list.is_allowed(&path, b"nix-flags = []").unwrap());
+        assert!(!allow_list
+            .is_allowed(&path, b"nix-flags = [\"--impure\"]")
+            .unwrap());
+
+        assert!(allow_list.deny(&path));
+        assert!(!allow_list.is_allowed(&path, b"nix-flags = []").unwrap());
+        assert!(!allow_list.deny(&path));
+    }
+}
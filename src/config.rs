@@ -0,0 +1,224 @@
+use std::collections::BTreeMap;
+
+use camino::Utf8Path;
+use camino::Utf8PathBuf;
+use miette::miette;
+use miette::Context;
+use miette::IntoDiagnostic;
+use miette::NamedSource;
+use miette::SourceSpan;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use crate::allow_list::AllowList;
+use crate::dirs;
+use crate::nix::NixShellBackend;
+use crate::nom::NomMode;
+use crate::nom::NomSetting;
+
+/// The name of the configuration file in the configuration directory.
+const CONFIG_FILE: &str = "config.toml";
+
+/// The name of per-project configuration files.
+pub const PROJECT_CONFIG_FILE: &str = ".nix-your-shell.toml";
+
+/// User configuration, read from `$XDG_CONFIG_HOME/nix-your-shell/config.toml`.
+///
+/// Command-line arguments and environment variables take precedence over these values.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    /// The shell to use if none is given on the command line.
+    pub shell: Option<String>,
+    /// Log filter directives, like `--log`.
+    pub log: Option<String>,
+    /// Print absolute paths to `nix-your-shell`, like `--absolute`.
+    #[serde(default)]
+    pub absolute: bool,
+    /// The program to run `nix-shell` invocations with, like `--backend`.
+    pub backend: Option<NixShellBackend>,
+    /// When to use `nom` instead of `nix`, like `--nom`.
+    ///
+    /// This is `"auto"`, `"always"`, or `"never"`; `true` means `"auto"`.
+    pub nom: Option<NomMode>,
+    /// Enter `nix develop` environments natively, like `--native`.
+    #[serde(default)]
+    pub native: bool,
+    /// Cache development environments, like `--cache`.
+    #[serde(default)]
+    pub cache: bool,
+    /// Read `nix`'s flags from the installed `nix`, like `--installed-nix-flags`.
+    #[serde(default)]
+    pub installed_nix_flags: bool,
+    /// Flags to add to every `nix` command, like `["--print-build-logs"]`.
+    #[serde(default)]
+    pub nix_flags: Vec<String>,
+    /// Commands to wrap besides `nix` and `nix-shell`, like `--wrap-command`.
+    #[serde(default)]
+    pub wrap_commands: Vec<String>,
+    /// Settings for subcommands, keyed by the subcommand's name (like `develop`, `flake update`,
+    /// or `nix-shell`).
+    #[serde(default)]
+    pub subcommands: BTreeMap<String, SubcommandConfig>,
+    /// Paths to templates to use instead of the built-in shell environment code, keyed by shell
+    /// (like `fish` or `nu`).
+    ///
+    /// Relative paths are resolved relative to the configuration directory.
+    #[serde(default)]
+    pub templates: BTreeMap<String, Utf8PathBuf>,
+}
+
+/// Settings for a `nix` subcommand or `nix-shell`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct SubcommandConfig {
+    /// Flags to add to the subcommand, after the global `nix-flags`.
+    #[serde(default)]
+    pub flags: Vec<String>,
+    /// Whether to run the shell in `nix develop`, `nix shell`, and `nix-shell` commands.
+    ///
+    /// If `false`, `nix-your-shell` leaves the arguments as they are, running `bash`.
+    pub wrap: Option<bool>,
+    /// How to use `nom` (or `nom-shell`) for the subcommand, overriding the default.
+    ///
+    /// This is `"native"`, `"json"`, `"never"`, or a boolean.
+    pub nom: Option<NomSetting>,
+    /// An installable to use if none is given, like `.#ci`.
+    pub default_installable: Option<String>,
+}
+
+/// Per-project configuration, read from a `.nix-your-shell.toml` in the current directory or one
+/// of its parents.
+///
+/// Project configuration is only used once the user has allowed it with `nix-your-shell allow`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ProjectConfig {
+    /// Flags to add to every `nix` command, after the user's `nix-flags`.
+    #[serde(default)]
+    pub nix_flags: Vec<String>,
+    /// Settings for subcommands, merged into the user's settings.
+    #[serde(default)]
+    pub subcommands: BTreeMap<String, SubcommandConfig>,
+}
+
+/// An error in the configuration file.
+#[derive(Debug, thiserror::Error, miette::Diagnostic)]
+#[error("Failed to parse configuration file {path}")]
+pub struct ConfigError {
+    path: Utf8PathBuf,
+    #[source_code]
+    source_code: NamedSource<String>,
+    #[label("{message}")]
+    span: Option<SourceSpan>,
+    message: String,
+}
+
+impl Config {
+    /// Load the configuration file, if it exists.
+    pub fn load() -> miette::Result<Self> {
+        let path = dirs::config_dir()?.join(CONFIG_FILE);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        parse(path)
+    }
+
+    /// Merge project configuration into this configuration.
+    ///
+    /// Flags are added after the user's flags, and other settings override the user's settings.
+    pub fn merge(&mut self, project: ProjectConfig) {
+        self.nix_flags.extend(project.nix_flags);
+        for (name, project) in project.subcommands {
+            let subcommand = self.subcommands.entry(name).or_default();
+            subcommand.flags.extend(project.flags);
+            subcommand.wrap = project.wrap.or(subcommand.wrap);
+            subcommand.nom = project.nom.or(subcommand.nom);
+            subcommand.default_installable = project
+                .default_installable
+                .or(subcommand.default_installable.take());
+        }
+    }
+
+    /// The settings for a subcommand, like `develop` or `nix-shell`.
+    pub fn subcommand(&self, name: &str) -> Option<&SubcommandConfig> {
+        self.subcommands.get(name)
+    }
+}
+
+impl ProjectConfig {
+    /// Find and load the project configuration for the current directory, if it's allowed.
+    ///
+    /// Warns and returns `None` if the project configuration hasn't been allowed.
+    pub fn discover() -> miette::Result<Option<Self>> {
+        let Some(path) = find_project_config()? else {
+            return Ok(None);
+        };
+        // Read the file once, so that it can't change between being checked and being parsed.
+        let contents = read(&path)?;
+        if !AllowList::load()?.is_allowed(&path, contents.as_bytes())? {
+            tracing::warn!(
+                "Ignoring {path} because it isn't allowed or has changed\n\
+                Note: Run `nix-your-shell allow` to use its settings"
+            );
+            return Ok(None);
+        }
+        tracing::debug!(%path, "Loading project configuration");
+        parse_str(path, contents).map(Some)
+    }
+}
+
+/// Find the nearest `.nix-your-shell.toml` in the current directory or its parents.
+///
+/// The search stops at the root of the flake or `git` repository containing the current
+/// directory.
+pub fn find_project_config() -> miette::Result<Option<Utf8PathBuf>> {
+    let current_dir = std::env::current_dir()
+        .into_diagnostic()
+        .wrap_err("Unable to determine current directory")?;
+    let current_dir = Utf8PathBuf::from_path_buf(current_dir)
+        .map_err(|path_buf| miette!("Path is not UTF-8: {path_buf:?}"))?;
+
+    for dir in current_dir.ancestors() {
+        let path = dir.join(PROJECT_CONFIG_FILE);
+        if path.is_file() {
+            return Ok(Some(path));
+        }
+        if is_project_root(dir) {
+            tracing::trace!(%dir, "Reached project root without finding {PROJECT_CONFIG_FILE}");
+            break;
+        }
+    }
+    Ok(None)
+}
+
+/// Is `dir` the root of a flake or `git` repository?
+fn is_project_root(dir: &Utf8Path) -> bool {
+    dir.join("flake.nix").exists() || dir.join(".git").exists()
+}
+
+/// Read and parse a TOML configuration file.
+pub fn parse<T: DeserializeOwned>(path: Utf8PathBuf) -> miette::Result<T> {
+    let contents = read(&path)?;
+    parse_str(path, contents)
+}
+
+/// Read a configuration file.
+pub fn read(path: &Utf8Path) -> miette::Result<String> {
+    std::fs::read_to_string(path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to read {path}"))
+}
+
+/// Parse the `contents` of the TOML configuration file at `path`.
+pub fn parse_str<T: DeserializeOwned>(path: Utf8PathBuf, contents: String) -> miette::Result<T> {
+    toml::from_str(&contents).map_err(|err| {
+        ConfigError {
+            span: err.span().map(SourceSpan::from),
+            message: err.message().to_owned(),
+            source_code: NamedSource::new(&path, contents),
+            path,
+        }
+        .into()
+    })
+}
@@ -0,0 +1,438 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::process;
+
+use miette::miette;
+use miette::Context;
+use miette::IntoDiagnostic;
+use serde::Deserialize;
+
+use crate::quote;
+use crate::shell::OilsShell;
+use crate::shell::ShellKind;
+
+/// Variables which `nix develop` doesn't copy from the build environment into the shell.
+///
+/// See: <https://github.com/NixOS/nix/blob/master/src/nix/develop.cc>
+const IGNORED_VARS: &[&str] = &[
+    "BASHOPTS",
+    "HOME",
+    "NIX_BUILD_TOP",
+    "NIX_ENFORCE_PURITY",
+    "NIX_LOG_FD",
+    "NIX_REMOTE",
+    "PPID",
+    "SHELL",
+    "SHELLOPTS",
+    "SHLVL",
+    "SSL_CERT_FILE",
+    "TEMP",
+    "TEMPDIR",
+    "TERM",
+    "TMP",
+    "TMPDIR",
+    "TZ",
+    "UID",
+];
+
+/// Variables which `nix develop` prepends to the user's existing values rather than replacing.
+const SAVED_VARS: &[&str] = &["PATH", "XDG_DATA_DIRS"];
+
+/// A development environment, as printed by `nix print-dev-env --json`.
+#[derive(Debug, Deserialize)]
+pub struct DevEnv {
+    variables: BTreeMap<String, Variable>,
+}
+
+/// A shell variable in a development environment.
+///
+/// Only `exported` variables can be represented in shells other than `bash`; the rest (`var`,
+/// `array`, and `associative`) are used by the `bash` functions in `stdenv`'s setup script.
+#[derive(Debug, Deserialize)]
+struct Variable {
+    #[serde(rename = "type")]
+    kind: String,
+    value: serde_json::Value,
+}
+
+impl DevEnv {
+    /// Parse the output of `nix print-dev-env --json`.
+    pub fn from_json(json: &str) -> miette::Result<Self> {
+        serde_json::from_str(json)
+            .into_diagnostic()
+            .wrap_err("Failed to parse `nix print-dev-env --json` output")
+    }
+
+    /// Run `nix` with the given `print-dev-env` arguments and parse its output.
+    pub fn from_nix(args: &[String]) -> miette::Result<Self> {
+        Self::from_json(&Self::json_from_nix(args)?)
+    }
+
+    /// Run `nix` with the given `print-dev-env` arguments and return its output.
+    pub fn json_from_nix(args: &[String]) -> miette::Result<String> {
+        let command =
+            shell_words::join(std::iter::once("nix").chain(args.iter().map(|s| s.as_str())));
+        tracing::debug!(%command, "Evaluating development environment");
+        let output = process::Command::new("nix")
+            .args(args)
+            .stderr(process::Stdio::inherit())
+            .output()
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Unable to launch {command}"))?;
+        if !output.status.success() {
+            return Err(miette!("{command} failed: {}", output.status));
+        }
+        String::from_utf8(output.stdout)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("{command} printed invalid UTF-8"))
+    }
+
+    /// The environment variables to set in the shell, in the same manner as `nix develop`.
+    ///
+    /// Values for `$PATH` and `$XDG_DATA_DIRS` are prepended to the current process's values.
+    pub fn exported(&self) -> Vec<(String, String)> {
+        self.exported_with(|name| std::env::var(name).ok())
+    }
+
+    /// Like [`DevEnv::exported`], but with the current values of `$PATH` and `$XDG_DATA_DIRS`
+    /// looked up with `current`.
+    fn exported_with(&self, current: impl Fn(&str) -> Option<String>) -> Vec<(String, String)> {
+        let mut ret: Vec<(String, String)> = self
+            .variables
+            .iter()
+            .filter(|(name, _)| !IGNORED_VARS.contains(&name.as_str()))
+            .filter(|(_, variable)| variable.kind == "exported")
+            .filter_map(|(name, variable)| {
+                let value = variable.value.as_str()?;
+                let value = match current(name) {
+                    Some(saved) if SAVED_VARS.contains(&name.as_str()) && !saved.is_empty() => {
+                        format!("{value}:{saved}")
+                    }
+                    _ => value.to_owned(),
+                };
+                Some((name.clone(), value))
+            })
+            .collect();
+        ret.push(("IN_NIX_SHELL".into(), "impure".into()));
+        ret
+    }
+
+    /// Render shell code which sets this environment in the given shell.
+    pub fn render(&self, kind: &ShellKind) -> miette::Result<String> {
+        let mut ret = String::new();
+        for (name, value) in self.exported() {
+            let line = match kind {
+                ShellKind::Zsh
+                | ShellKind::Bash
+                | ShellKind::Sh
+                | ShellKind::Dash
+                | ShellKind::Ksh
+                | ShellKind::Mksh
+                | ShellKind::Oils(OilsShell::Osh) => {
+                    format!("export {name}={}", quote::posix(&value))
+                }
+
+                ShellKind::Fish => {
+                    if name.ends_with("PATH") {
+                        // Fish splits path variables into lists.
+                        let elements = value
+                            .split(':')
+                            .map(quote::fish)
+                            .collect::<Vec<_>>()
+                            .join(" ");
+                        format!("set -gx {name} {elements}")
+                    } else {
+                        format!("set -gx {name} {}", quote::fish(&value))
+                    }
+                }
+
+                ShellKind::Nushell => {
+                    if name == "PATH" {
+                        format!(
+                            "$env.{name} = ({} | split row (char esep))",
+                            quote::nu(&value)
+                        )
+                    } else {
+                        format!("$env.{name} = {}", quote::nu(&value))
+                    }
+                }
+
+                ShellKind::Xonsh => {
+                    format!("${name} = {}", quote::xonsh(&value))
+                }
+
+                ShellKind::Elvish => {
+                    format!("set-env {name} {}", quote::elvish(&value))
+                }
+
+                ShellKind::PowerShell => {
+                    format!("${{env:{name}}} = {}", quote::powershell(&value))
+                }
+
+                ShellKind::Tcsh => {
+                    format!("setenv {name} {}", quote::tcsh(&value))
+                }
+
+                ShellKind::Oils(OilsShell::Ysh) => {
+                    format!("setglobal ENV.{name} = {}", quote::ysh(&value))
+                }
+
+                ShellKind::Murex | ShellKind::Ion | ShellKind::Other(_) => {
+                    return Err(miette!(
+                        "I don't know how to set a development environment for `{kind}`\n\
+                        Note: Supported shells are: {}",
+                        ShellKind::list("and", supports)
+                    ))
+                }
+            };
+            let _ = writeln!(ret, "{line}");
+        }
+        Ok(ret)
+    }
+}
+
+/// Can development environments be rendered as code for the given shell?
+pub fn supports(kind: &ShellKind) -> bool {
+    !matches!(
+        kind,
+        ShellKind::Murex | ShellKind::Ion | ShellKind::Other(_)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Trimmed `nix print-dev-env --json` output for a `mkShell` with `hello` and a `shellHook`.
+    const FIXTURE: &str = include_str!("../tests/fixtures/print-dev-env.json");
+
+    fn fixture() -> DevEnv {
+        DevEnv::from_json(FIXTURE).unwrap()
+    }
+
+    fn dev_env(variables: &[(&str, &str)]) -> DevEnv {
+        DevEnv {
+            variables: variables
+                .iter()
+                .map(|(name, value)| {
+                    let variable = Variable {
+                        kind: "exported".to_owned(),
+                        value: serde_json::Value::from(*value),
+                    };
+                    (name.to_string(), variable)
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_exported_filters_variables() {
+        let exported = fixture().exported_with(|_| None);
+        let names = exported
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            names,
+            [
+                "AR",
+                "CC",
+                "NIX_BUILD_CORES",
+                "NIX_CFLAGS_COMPILE",
+                "NIX_ENFORCE_NO_NATIVE",
+                "NIX_STORE",
+                "PATH",
+                "XDG_DATA_DIRS",
+                "buildInputs",
+                "builder",
+                "name",
+                "nativeBuildInputs",
+                "out",
+                "shellHook",
+                "stdenv",
+                "strictDeps",
+                "system",
+                "IN_NIX_SHELL",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_exported_skips_internal_variables() {
+        let exported = fixture().exported_with(|_| None);
+        let has = |name: &str| exported.iter().any(|(exported, _)| exported == name);
+
+        // `var`s are internal to the setup script.
+        assert!(!has("BASH"));
+        assert!(!has("phases"));
+        // Arrays and associative arrays can't be exported.
+        assert!(!has("pkgsHostTarget"));
+        assert!(!has("outputs"));
+        // Functions aren't variables at all.
+        assert!(!has("genericBuild"));
+        // `nix develop` doesn't copy these from the build environment.
+        assert!(!has("HOME"));
+        assert!(!has("NIX_BUILD_TOP"));
+        assert!(!has("NIX_LOG_FD"));
+        assert!(!has("SHELL"));
+        assert!(!has("SSL_CERT_FILE"));
+        assert!(!has("TMPDIR"));
+    }
+
+    #[test]
+    fn test_exported_values() {
+        let exported = fixture().exported_with(|_| None);
+        let value = |name: &str| {
+            exported
+                .iter()
+                .find(|(exported, _)| exported == name)
+                .map(|(_, value)| value.as_str())
+        };
+
+        assert_eq!(
+            value("shellHook"),
+            Some("echo \"Welcome to $name!\"\nexport GREETING='hi'\n")
+        );
+        assert_eq!(value("buildInputs"), Some(""));
+        assert_eq!(value("IN_NIX_SHELL"), Some("impure"));
+    }
+
+    #[test]
+    fn test_exported_prepends_saved_variables() {
+        let exported = fixture().exported_with(|name| match name {
+            "PATH" => Some("/usr/bin:/bin".to_owned()),
+            "XDG_DATA_DIRS" => Some(String::new()),
+            "AR" => Some("llvm-ar".to_owned()),
+            _ => None,
+        });
+        let value = |name: &str| {
+            exported
+                .iter()
+                .find(|(exported, _)| exported == name)
+                .map(|(_, value)| value.as_str())
+        };
+
+        assert_eq!(
+            value("PATH"),
+            Some(
+                "/nix/store/9zq2a4yj0lqq4lbvxs4yzh7cwwsw0b9m-hello-2.12.1/bin:\
+                /nix/store/62zpnw69ylcfhcpy1di8152zlzmbls91-coreutils-9.5/bin:\
+                /usr/bin:/bin"
+            )
+        );
+        // Empty values aren't prepended to.
+        assert_eq!(
+            value("XDG_DATA_DIRS"),
+            Some("/nix/store/9zq2a4yj0lqq4lbvxs4yzh7cwwsw0b9m-hello-2.12.1/share")
+        );
+        // Other variables are replaced.
+        assert_eq!(value("AR"), Some("ar"));
+    }
+
+    #[test]
+    fn test_render() {
+        let dev_env = dev_env(&[
+            ("GREETING", "it's \"$HOME\" \\o/"),
+            ("MANPATH", "/a:/b c"),
+            ("PATH", "/nix/store/x/bin"),
+        ]);
+        let render = |kind: ShellKind| {
+            let rendered = dev_env.render(&kind).unwrap();
+            // Skip `$PATH`, which depends on the environment the tests run in.
+            rendered
+                .lines()
+                .filter(|line| !line.contains("/nix/store/x/bin"))
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+
+        let posix = "export GREETING='it'\\''s \"$HOME\" \\o/'\n\
+            export MANPATH='/a:/b c'\n\
+            export IN_NIX_SHELL=impure";
+        for kind in [
+            ShellKind::Bash,
+            ShellKind::Zsh,
+            ShellKind::Sh,
+            ShellKind::Dash,
+            ShellKind::Ksh,
+            ShellKind::Mksh,
+            ShellKind::Oils(OilsShell::Osh),
+        ] {
+            assert_eq!(render(kind), posix);
+        }
+
+        assert_eq!(
+            render(ShellKind::Fish),
+            "set -gx GREETING 'it\\'s \"$HOME\" \\\\o/'\n\
+            set -gx MANPATH '/a' '/b c'\n\
+            set -gx IN_NIX_SHELL 'impure'"
+        );
+        assert_eq!(
+            render(ShellKind::Nushell),
+            "$env.GREETING = r#'it's \"$HOME\" \\o/'#\n\
+            $env.MANPATH = r#'/a:/b c'#\n\
+            $env.IN_NIX_SHELL = r#'impure'#"
+        );
+        assert_eq!(
+            render(ShellKind::Xonsh),
+            "$GREETING = \"it's \\\"$HOME\\\" \\\\o/\"\n\
+            $MANPATH = \"/a:/b c\"\n\
+            $IN_NIX_SHELL = \"impure\""
+        );
+        assert_eq!(
+            render(ShellKind::Elvish),
+            "set-env GREETING 'it''s \"$HOME\" \\o/'\n\
+            set-env MANPATH '/a:/b c'\n\
+            set-env IN_NIX_SHELL 'impure'"
+        );
+        assert_eq!(
+            render(ShellKind::PowerShell),
+            "${env:GREETING} = 'it''s \"$HOME\" \\o/'\n\
+            ${env:MANPATH} = '/a:/b c'\n\
+            ${env:IN_NIX_SHELL} = 'impure'"
+        );
+        assert_eq!(
+            render(ShellKind::Tcsh),
+            "setenv GREETING 'it'\\''s \"$HOME\" \\o/'\n\
+            setenv MANPATH '/a:/b c'\n\
+            setenv IN_NIX_SHELL 'impure'"
+        );
+        assert_eq!(
+            render(ShellKind::Oils(OilsShell::Ysh)),
+            "setglobal ENV.GREETING = u'it\\'s \"$HOME\" \\\\o/'\n\
+            setglobal ENV.MANPATH = u'/a:/b c'\n\
+            setglobal ENV.IN_NIX_SHELL = u'impure'"
+        );
+    }
+
+    #[test]
+    fn test_render_tcsh_newlines() {
+        let dev_env = dev_env(&[("HOOK", "echo hi!\necho bye")]);
+        let rendered = dev_env.render(&ShellKind::Tcsh).unwrap();
+        assert!(rendered.starts_with("setenv HOOK 'echo hi\\!\\\necho bye'\n"));
+    }
+
+    #[test]
+    fn test_render_path() {
+        let dev_env = dev_env(&[("PATH", "/nix/store/x/bin")]);
+        let rendered = dev_env.render(&ShellKind::Fish).unwrap();
+        let path = rendered.lines().next().unwrap();
+        assert!(path.starts_with("set -gx PATH '/nix/store/x/bin' "));
+
+        let rendered = dev_env.render(&ShellKind::Nushell).unwrap();
+        let path = rendered.lines().next().unwrap();
+        assert!(path.starts_with("$env.PATH = (r#'/nix/store/x/bin:"));
+        assert!(path.ends_with("'# | split row (char esep))"));
+    }
+
+    #[test]
+    fn test_render_unsupported() {
+        let dev_env = dev_env(&[("GREETING", "hello")]);
+        assert!(dev_env.render(&ShellKind::Murex).is_err());
+        assert!(dev_env.render(&ShellKind::Ion).is_err());
+        assert!(dev_env
+            .render(&ShellKind::Other("numbat".to_owned()))
+            .is_err());
+    }
+}
@@ -0,0 +1,273 @@
+use std::time::SystemTime;
+
+use camino::Utf8Path;
+use camino::Utf8PathBuf;
+use miette::Context;
+use miette::IntoDiagnostic;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+
+use crate::dev_env::DevEnv;
+use crate::dirs;
+use crate::fs;
+
+/// The name of the directory in the cache directory holding cached development environments.
+const CACHE_DIR: &str = "dev-env";
+
+/// Files in a flake's directory which determine its development environments.
+const FLAKE_FILES: &[&str] = &["flake.nix", "flake.lock"];
+
+/// Flags which make `nix develop` evaluate something other than a flake.
+const NON_FLAKE_FLAGS: &[&str] = &["-f", "--file", "--expr"];
+
+/// A cache of `nix print-dev-env --json` output, like `nix-direnv`.
+///
+/// Each entry is keyed on the `print-dev-env` arguments, the flake's directory, and the system,
+/// and is reused until the flake's `flake.nix` or `flake.lock` changes.
+///
+/// The environments are kept alive with a `nix` profile next to each entry, which acts as a
+/// garbage collector root.
+pub struct DevEnvCache {
+    dir: Utf8PathBuf,
+}
+
+/// Metadata about a cached development environment.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    /// The `print-dev-env` arguments, not including `--profile`.
+    pub args: Vec<String>,
+    /// The directory of the flake the environment was evaluated from.
+    pub flake_dir: Utf8PathBuf,
+    /// A hash of the flake's `flake.nix` and `flake.lock`, to detect stale entries.
+    fingerprint: String,
+    /// When the entry was created, in seconds since the Unix epoch.
+    pub created: u64,
+}
+
+impl Entry {
+    /// Is this entry out of date with its flake?
+    pub fn is_stale(&self) -> bool {
+        fingerprint(&self.flake_dir).map_or(true, |fingerprint| fingerprint != self.fingerprint)
+    }
+}
+
+impl DevEnvCache {
+    /// Open the cache in `$XDG_CACHE_HOME/nix-your-shell/dev-env`.
+    pub fn open() -> miette::Result<Self> {
+        Ok(Self {
+            dir: dirs::cache_dir()?.join(CACHE_DIR),
+        })
+    }
+
+    /// Get the development environment for the given `print-dev-env` arguments and `installable`
+    /// (like `.#default`), evaluating it if it isn't cached or is out of date.
+    ///
+    /// Environments which don't come from a local flake aren't cached.
+    pub fn dev_env(&self, args: &[String], installable: Option<&str>) -> miette::Result<DevEnv> {
+        if args
+            .iter()
+            .any(|arg| NON_FLAKE_FLAGS.contains(&arg.as_str()))
+        {
+            tracing::debug!("Not caching development environment which isn't from a flake");
+            return DevEnv::from_nix(args);
+        }
+        let Some(flake_dir) = flake_dir(installable.unwrap_or("."))? else {
+            tracing::debug!(
+                ?installable,
+                "Not caching development environment which isn't from a local flake"
+            );
+            return DevEnv::from_nix(args);
+        };
+        let fingerprint = fingerprint(&flake_dir)?;
+
+        let mut hasher = Sha256::new();
+        for part in args
+            .iter()
+            .map(String::as_str)
+            .chain([flake_dir.as_str(), &system()])
+        {
+            hasher.update(part);
+            hasher.update([0]);
+        }
+        let key = format!("{:x}", hasher.finalize());
+        let entry_path = self.dir.join(format!("{key}.json"));
+        let env_path = self.dir.join(format!("{key}.env.json"));
+
+        if let Ok(contents) = std::fs::read_to_string(&entry_path) {
+            match serde_json::from_str::<Entry>(&contents) {
+                Ok(entry) if entry.fingerprint == fingerprint => {
+                    match std::fs::read_to_string(&env_path) {
+                        Ok(json) => {
+                            tracing::debug!(path = %env_path, "Using cached development environment");
+                            return DevEnv::from_json(&json);
+                        }
+                        Err(err) => {
+                            tracing::debug!(path = %env_path, %err, "Failed to read cached development environment");
+                        }
+                    }
+                }
+                Ok(_) => {
+                    tracing::debug!(path = %entry_path, "Cached development environment is out of date");
+                }
+                Err(err) => {
+                    tracing::debug!(path = %entry_path, %err, "Failed to parse cache entry");
+                }
+            }
+        }
+
+        std::fs::create_dir_all(&self.dir)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to create {}", self.dir))?;
+        let mut profile_args = args.to_vec();
+        profile_args.push("--profile".into());
+        profile_args.push(self.dir.join(format!("{key}.profile")).into_string());
+        let json = DevEnv::json_from_nix(&profile_args)?;
+        let dev_env = DevEnv::from_json(&json)?;
+
+        let entry = Entry {
+            args: args.to_vec(),
+            flake_dir,
+            fingerprint,
+            created: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_secs()),
+        };
+        let result = fs::write_atomic(&env_path, &json).and_then(|()| {
+            fs::write_atomic(
+                &entry_path,
+                &serde_json::to_string(&entry).into_diagnostic()?,
+            )
+        });
+        if let Err(err) = result {
+            tracing::warn!("Failed to cache development environment: {err:?}");
+        }
+        Ok(dev_env)
+    }
+
+    /// The cached development environments, keyed by their cache keys.
+    pub fn entries(&self) -> miette::Result<Vec<(String, Entry)>> {
+        let Ok(read_dir) = self.dir.read_dir_utf8() else {
+            return Ok(Vec::new());
+        };
+        let mut entries = Vec::new();
+        for dir_entry in read_dir {
+            let dir_entry = dir_entry.into_diagnostic()?;
+            let name = dir_entry.file_name();
+            let Some(key) = name.strip_suffix(".json") else {
+                continue;
+            };
+            if key.ends_with(".env") {
+                continue;
+            }
+            let contents = std::fs::read_to_string(dir_entry.path())
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to read {}", dir_entry.path()))?;
+            match serde_json::from_str::<Entry>(&contents) {
+                Ok(entry) => entries.push((key.to_owned(), entry)),
+                Err(err) => {
+                    tracing::warn!(path = %dir_entry.path(), %err, "Failed to parse cache entry");
+                }
+            }
+        }
+        entries.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.created));
+        Ok(entries)
+    }
+
+    /// Remove the cached development environment with the given key, including its garbage
+    /// collector roots.
+    pub fn remove(&self, key: &str) -> miette::Result<()> {
+        let Ok(read_dir) = self.dir.read_dir_utf8() else {
+            return Ok(());
+        };
+        for dir_entry in read_dir {
+            let dir_entry = dir_entry.into_diagnostic()?;
+            // The profile is `{key}.profile`, linking to generations like `{key}.profile-1-link`.
+            if dir_entry.file_name().starts_with(&format!("{key}.")) {
+                std::fs::remove_file(dir_entry.path())
+                    .into_diagnostic()
+                    .wrap_err_with(|| format!("Failed to remove {}", dir_entry.path()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove all cached development environments.
+    pub fn clear(&self) -> miette::Result<()> {
+        if !self.dir.exists() {
+            return Ok(());
+        }
+        std::fs::remove_dir_all(&self.dir)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to remove {}", self.dir))
+    }
+}
+
+/// Find the directory of the local flake for an installable like `.#default` or
+/// `path:../foo#bar`.
+///
+/// Like `nix`, this searches the parent directories for a `flake.nix`, stopping at the root of
+/// the `git` repository.
+fn flake_dir(installable: &str) -> miette::Result<Option<Utf8PathBuf>> {
+    let flake_ref = installable
+        .split_once('#')
+        .map_or(installable, |(flake_ref, _)| flake_ref);
+    let flake_ref = flake_ref.strip_prefix("path:").unwrap_or(flake_ref);
+    let flake_ref = flake_ref
+        .split_once('?')
+        .map_or(flake_ref, |(path, _)| path);
+    let path = match flake_ref {
+        "" => ".",
+        // Flake references like `github:owner/repo` or `nixpkgs`.
+        path if path.contains(':') || !(path.starts_with('.') || path.starts_with('/')) => {
+            return Ok(None)
+        }
+        path => path,
+    };
+    let Ok(path) = Utf8Path::new(path).canonicalize_utf8() else {
+        return Ok(None);
+    };
+    for dir in path.ancestors() {
+        if dir.join("flake.nix").is_file() {
+            return Ok(Some(dir.to_owned()));
+        }
+        if dir.join(".git").exists() {
+            break;
+        }
+    }
+    Ok(None)
+}
+
+/// A hash of the files in a flake's directory which determine its development environments.
+fn fingerprint(flake_dir: &Utf8Path) -> miette::Result<String> {
+    let mut hasher = Sha256::new();
+    for file in FLAKE_FILES {
+        let path = flake_dir.join(file);
+        hasher.update(file);
+        match std::fs::read(&path) {
+            Ok(contents) => {
+                hasher.update([1]);
+                hasher.update(contents);
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                hasher.update([0]);
+            }
+            Err(err) => {
+                return Err(err)
+                    .into_diagnostic()
+                    .wrap_err_with(|| format!("Failed to read {path}"))
+            }
+        }
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// The Nix system we're running on, like `x86_64-linux` or `aarch64-darwin`.
+fn system() -> String {
+    let os = match std::env::consts::OS {
+        "macos" => "darwin",
+        os => os,
+    };
+    format!("{}-{os}", std::env::consts::ARCH)
+}
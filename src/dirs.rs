@@ -0,0 +1,40 @@
+use camino::Utf8PathBuf;
+use miette::miette;
+
+/// The directory `nix-your-shell` stores cached data in.
+///
+/// This is `$XDG_CACHE_HOME/nix-your-shell`, or `~/.cache/nix-your-shell` if `$XDG_CACHE_HOME`
+/// isn't set.
+pub fn cache_dir() -> miette::Result<Utf8PathBuf> {
+    xdg_dir("XDG_CACHE_HOME", ".cache")
+}
+
+/// The directory `nix-your-shell` reads configuration from.
+///
+/// This is `$XDG_CONFIG_HOME/nix-your-shell`, or `~/.config/nix-your-shell` if
+/// `$XDG_CONFIG_HOME` isn't set.
+pub fn config_dir() -> miette::Result<Utf8PathBuf> {
+    xdg_dir("XDG_CONFIG_HOME", ".config")
+}
+
+/// The directory `nix-your-shell` stores persistent data in.
+///
+/// This is `$XDG_DATA_HOME/nix-your-shell`, or `~/.local/share/nix-your-shell` if
+/// `$XDG_DATA_HOME` isn't set.
+pub fn data_dir() -> miette::Result<Utf8PathBuf> {
+    xdg_dir("XDG_DATA_HOME", ".local/share")
+}
+
+/// Get `${var}/nix-your-shell`, falling back to `~/{default}/nix-your-shell` if `var` is unset or
+/// empty.
+fn xdg_dir(var: &str, default: &str) -> miette::Result<Utf8PathBuf> {
+    let base = match std::env::var(var) {
+        Ok(dir) if !dir.is_empty() => Utf8PathBuf::from(dir),
+        _ => {
+            let home =
+                std::env::var("HOME").map_err(|_| miette!("Neither ${var} nor $HOME is set"))?;
+            Utf8PathBuf::from(home).join(default)
+        }
+    };
+    Ok(base.join("nix-your-shell"))
+}
@@ -0,0 +1,84 @@
+use camino::Utf8Path;
+use camino::Utf8PathBuf;
+use miette::Context;
+use miette::IntoDiagnostic;
+
+/// Write a file atomically, so that concurrent readers never see it partially written.
+///
+/// The contents are written to a temporary file in the same directory, which is then renamed
+/// into place.
+pub fn write_atomic(path: &Utf8Path, contents: &str) -> miette::Result<()> {
+    let temp_path = Utf8PathBuf::from(format!("{path}.tmp-{}", std::process::id()));
+    std::fs::write(&temp_path, contents)
+        .and_then(|()| std::fs::rename(&temp_path, path))
+        .inspect_err(|_| {
+            let _ = std::fs::remove_file(&temp_path);
+        })
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to write {path}"))
+}
+
+/// A temporary directory for tests, which is removed when it's dropped.
+#[cfg(test)]
+pub struct TempDir(Utf8PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    pub fn new() -> Self {
+        static COUNT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let count = COUNT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let path = Utf8PathBuf::from_path_buf(std::env::temp_dir())
+            .expect("The temporary directory is UTF-8")
+            .join(format!(
+                "nix-your-shell-test-{}-{count}",
+                std::process::id()
+            ));
+        std::fs::create_dir_all(&path).expect("Failed to create temporary directory");
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Utf8Path {
+        &self.0
+    }
+
+    /// Write a file named `name` in the directory, returning its path.
+    pub fn write(&self, name: &str, contents: &str) -> Utf8PathBuf {
+        let path = self.0.join(name);
+        std::fs::write(&path, contents).expect("Failed to write temporary file");
+        path
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_atomic() {
+        let dir = TempDir::new();
+        let path = dir.path().join("entry.json");
+
+        write_atomic(&path, "old").unwrap();
+        write_atomic(&path, "new").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+        // The temporary file was renamed into place.
+        assert_eq!(dir.path().read_dir_utf8().unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_temp_dir() {
+        let dir = TempDir::new();
+        let path = dir.path().to_owned();
+        assert_ne!(path, TempDir::new().path());
+        dir.write("file", "contents");
+        drop(dir);
+        assert!(!path.exists());
+    }
+}
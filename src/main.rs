@@ -1,10 +1,16 @@
 #[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+#[cfg(unix)]
 use std::os::unix::process::CommandExt;
 use std::process;
 
 use calm_io::stdout as println;
+use calm_io::stdoutln;
 use camino::Utf8Path;
 use camino::Utf8PathBuf;
+use clap::builder::BoolishValueParser;
+use clap::builder::TypedValueParser;
+use clap::CommandFactory;
 use clap::Parser;
 use miette::miette;
 use miette::Context;
@@ -15,6 +21,43 @@ use shell::Shell;
 use shell::ShellKind;
 
 mod nix;
+use nix::NixShellBackend;
+
+mod nom;
+use nom::NomMode;
+use nom::NomPolicy;
+
+mod dev_env;
+use dev_env::DevEnv;
+
+mod dev_env_cache;
+use dev_env_cache::DevEnvCache;
+
+mod dirs;
+
+mod fs;
+
+mod nix_cli;
+use nix_cli::NixCli;
+
+mod config;
+use config::Config;
+use config::ProjectConfig;
+
+mod allow_list;
+use allow_list::AllowList;
+
+mod template;
+use template::Template;
+
+mod quote;
+
+mod wrapped;
+use wrapped::WrappedCommand;
+
+mod prompt;
+use prompt::PromptFormat;
+use prompt::ShellInfo;
 
 /// Environment variable that indicates that the Nix profile has already been sourced.
 ///
@@ -39,142 +82,830 @@ pub struct Opts {
     /// except the level are optional.
     ///
     /// Try `debug` or `trace`.
-    #[arg(long, default_value = "info", env = "NIX_YOUR_SHELL_LOG")]
-    log: String,
+    ///
+    /// Defaults to `info`.
+    #[arg(long, env = "NIX_YOUR_SHELL_LOG")]
+    log: Option<String>,
 
     /// Print absolute paths to `nix-your-shell` in shell environment code.
     ///
     /// Note that this will not transform the shell argument to an absolute path.
     ///
     /// Absolute paths are automatically printed if `nix-your-shell` isn't on the `$PATH`.
-    #[arg(long)]
-    absolute: bool,
+    #[arg(
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_parser = BoolishValueParser::new(),
+        overrides_with = "no_absolute"
+    )]
+    absolute: Option<bool>,
+
+    /// Don't print absolute paths to `nix-your-shell`, even if the configuration file enables it.
+    #[arg(long, overrides_with = "absolute")]
+    no_absolute: bool,
 
     /// Use `nom` (`nix-output-monitor`) instead of `nix` for running commands.
+    ///
+    /// `--nom` on its own is `--nom=auto`, which falls back to `nix` (warning once per session)
+    /// if `nom` isn't installed.
+    #[arg(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "auto"
+    )]
+    nom: Option<NomMode>,
+
+    /// The program to run `nix-shell` invocations with.
+    ///
+    /// Falls back to `nix-shell` if the program isn't installed.
+    #[arg(long, value_enum, env = "NIX_YOUR_SHELL_BACKEND")]
+    backend: Option<NixShellBackend>,
+
+    /// Enter `nix develop` environments natively.
+    ///
+    /// Rather than starting `bash` and then running the shell from there, evaluate the environment
+    /// with `nix print-dev-env` and start the shell with the environment already set.
+    ///
+    /// Note that `shellHook` and the `bash` functions from `stdenv` (like `genericBuild`) are not
+    /// available in the shell.
+    ///
+    /// Defaults to `$NIX_YOUR_SHELL_NATIVE`.
+    #[arg(
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_parser = BoolishValueParser::new(),
+        overrides_with = "no_native"
+    )]
+    native: Option<bool>,
+
+    /// Don't enter `nix develop` environments natively, even if the configuration file or
+    /// `$NIX_YOUR_SHELL_NATIVE` enables it.
+    #[arg(long, overrides_with = "native")]
+    no_native: bool,
+
+    /// Cache development environments evaluated for `--native` and `print-dev-env`, like
+    /// `nix-direnv`.
+    ///
+    /// Environments from local flakes are cached in `$XDG_CACHE_HOME/nix-your-shell` until the
+    /// flake's `flake.nix` or `flake.lock` changes.
+    ///
+    /// Defaults to `$NIX_YOUR_SHELL_CACHE`.
+    #[arg(
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_parser = BoolishValueParser::new(),
+        overrides_with = "no_cache"
+    )]
+    cache: Option<bool>,
+
+    /// Don't cache development environments, even if the configuration file or
+    /// `$NIX_YOUR_SHELL_CACHE` enables it.
+    #[arg(long, overrides_with = "cache")]
+    no_cache: bool,
+
+    /// Read `nix`'s flags from the installed `nix` (with `nix __dump-cli`) rather than using the
+    /// built-in snapshot.
+    ///
+    /// The flags are cached in `$XDG_CACHE_HOME/nix-your-shell` until `nix` is upgraded.
+    ///
+    /// Defaults to `$NIX_YOUR_SHELL_INSTALLED_NIX_FLAGS`.
+    #[arg(
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        value_parser = BoolishValueParser::new(),
+        overrides_with = "no_installed_nix_flags"
+    )]
+    installed_nix_flags: Option<bool>,
+
+    /// Use the built-in snapshot of `nix`'s flags, even if the configuration file or
+    /// `$NIX_YOUR_SHELL_INSTALLED_NIX_FLAGS` enables `--installed-nix-flags`.
+    #[arg(long, overrides_with = "installed_nix_flags")]
+    no_installed_nix_flags: bool,
+
+    /// A `minijinja` template to render instead of the built-in shell environment code.
+    ///
+    /// Templates can also be set in the configuration file or placed in
+    /// `$XDG_CONFIG_HOME/nix-your-shell/templates/{shell}.j2`.
     #[arg(long)]
-    nom: bool,
+    template: Option<Utf8PathBuf>,
+
+    /// Also wrap this command in the shell environment code, like `devenv` or `nix-build`.
+    ///
+    /// May be given multiple times.
+    #[arg(long = "wrap-command", value_name = "COMMAND")]
+    wrap_commands: Vec<String>,
 
     /// The shell to use for wrapped commands and the shell environment.
     /// This can be an executable name like `fish` or the path to an executable like
     /// `/opt/homebrew/bin/fish`.
-    shell: String,
+    ///
+    /// Defaults to the `shell` in the configuration file, or the shell detected from the parent
+    /// process, `$SHELL`, or your passwd entry.
+    shell: Option<String>,
+
+    /// The kind of shell to generate code for, like `fish` or `zsh`.
+    ///
+    /// By default, this is detected from the shell's name. Use this for shells with names
+    /// `nix-your-shell` doesn't recognize, like a `fish` build installed as `my-fish`.
+    #[arg(long, value_name = "KIND")]
+    shell_kind: Option<ShellKind>,
 
     #[command(subcommand)]
     command: Option<Command>,
 }
 
-#[derive(Debug, Clone, clap::Subcommand)]
+impl Opts {
+    /// Fill in flags which weren't given on the command line.
+    ///
+    /// The last of a flag and its `--no-*` flag on the command line wins. Otherwise, flags are
+    /// read from their environment variable (like `$NIX_YOUR_SHELL_NATIVE`), and then from the
+    /// configuration file.
+    ///
+    /// The environment variables aren't read by `clap`, which treats them like the flag being
+    /// given, so they would conflict with the `--no-*` flags.
+    fn resolve(&mut self, config: &Config) -> miette::Result<()> {
+        fn resolve(
+            flag: &mut Option<bool>,
+            no_flag: bool,
+            var: Option<&str>,
+            default: bool,
+        ) -> miette::Result<()> {
+            let from_env = match var {
+                Some(var) => env_flag(var)?,
+                None => None,
+            };
+            *flag = Some(
+                if no_flag { Some(false) } else { *flag }
+                    .or(from_env)
+                    .unwrap_or(default),
+            );
+            Ok(())
+        }
+
+        resolve(&mut self.absolute, self.no_absolute, None, config.absolute)?;
+        resolve(
+            &mut self.native,
+            self.no_native,
+            Some("NIX_YOUR_SHELL_NATIVE"),
+            config.native,
+        )?;
+        resolve(
+            &mut self.cache,
+            self.no_cache,
+            Some("NIX_YOUR_SHELL_CACHE"),
+            config.cache,
+        )?;
+        resolve(
+            &mut self.installed_nix_flags,
+            self.no_installed_nix_flags,
+            Some("NIX_YOUR_SHELL_INSTALLED_NIX_FLAGS"),
+            config.installed_nix_flags,
+        )?;
+        self.nom = self.nom.or(config.nom);
+        self.backend = self.backend.or(config.backend);
+        Ok(())
+    }
+
+    fn absolute(&self) -> bool {
+        self.absolute.unwrap_or_default()
+    }
+
+    fn native(&self) -> bool {
+        self.native.unwrap_or_default()
+    }
+
+    fn cache(&self) -> bool {
+        self.cache.unwrap_or_default()
+    }
+
+    fn installed_nix_flags(&self) -> bool {
+        self.installed_nix_flags.unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Default, clap::Subcommand)]
 pub enum Command {
     /// Print the shell environment code to use `nix-your-shell`.
     ///
     /// This generally prints functions for `nix` and `nix-shell` which will instead call
     /// `nix-your-shell nix ...` and `nix-your-shell nix-shell ...`.
+    #[default]
     Env,
     /// Execute a `nix-shell` command, running the shell if no command is explicitly given.
     NixShell { args: Vec<String> },
     /// Execute a `nix` command, running the shell if no command is explicitly given.
     Nix { args: Vec<String> },
+    /// Print shell code which sets a `nix develop` environment, like `nix print-dev-env` but for
+    /// the shell instead of `bash`.
+    ///
+    /// The arguments are the same as for `nix develop`.
+    PrintDevEnv { args: Vec<String> },
+    /// Enter the shell with packages on the `$PATH`, optionally inside development shells.
+    ///
+    /// This runs `nix shell` with the installables and packages, inside `nix develop` for each
+    /// development shell, and then runs the shell.
+    Enter {
+        /// A development shell to enter with `nix develop`, like `.#default`.
+        ///
+        /// May be given multiple times to enter nested development shells.
+        #[arg(long, short)]
+        develop: Vec<String>,
+        /// A package from `nixpkgs` to add to the `$PATH`, like `hello`.
+        ///
+        /// This is short for the `nixpkgs#hello` installable.
+        #[arg(long = "package", short)]
+        packages: Vec<String>,
+        /// Installables to add to the `$PATH`, like `nixpkgs#hello`.
+        installables: Vec<String>,
+    },
+    /// Print shell code for a prompt segment showing the packages and nesting depth of the
+    /// current Nix shell.
+    ///
+    /// This reads the `$NIX_YOUR_SHELL_DEPTH`, `$NIX_YOUR_SHELL_PACKAGES`, and
+    /// `$NIX_YOUR_SHELL_INSTALLABLES` environment variables, which are set in shells launched by
+    /// `nix-your-shell`.
+    Info,
+    /// Print information about the current Nix shell for prompt frameworks like `starship` and
+    /// `oh-my-posh`.
+    Prompt {
+        /// The format to print the information in.
+        #[arg(long, value_enum, default_value_t)]
+        format: PromptFormat,
+    },
+    /// Allow a project's `.nix-your-shell.toml` to change `nix` commands.
+    ///
+    /// The file has to be allowed again after it changes.
+    Allow {
+        /// The file to allow. Defaults to the nearest `.nix-your-shell.toml`.
+        path: Option<Utf8PathBuf>,
+    },
+    /// Stop allowing a project's `.nix-your-shell.toml`.
+    Deny {
+        /// The file to deny. Defaults to the nearest `.nix-your-shell.toml`.
+        path: Option<Utf8PathBuf>,
+    },
+    /// Execute a wrapped command (like `devenv`), running the shell if no command is explicitly
+    /// given.
+    #[command(external_subcommand)]
+    Wrapped(Vec<String>),
+    /// Manage cached development environments.
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
 }
 
-impl Default for Command {
-    fn default() -> Self {
-        Self::Env
-    }
+#[derive(Debug, Clone, clap::Subcommand)]
+pub enum CacheCommand {
+    /// List cached development environments.
+    List,
+    /// Remove all cached development environments.
+    Clear,
+    /// Remove cached development environments which are out of date or whose flakes no longer
+    /// exist.
+    ///
+    /// Their store paths can then be removed with `nix-collect-garbage`.
+    Gc,
 }
 
 fn main() -> miette::Result<()> {
-    let opts = Opts::parse();
-    install_tracing(&opts.log)?;
-
-    let shell = Shell::from_path(&opts.shell)?;
-    tracing::debug!(%shell, input=opts.shell, "Detected shell");
+    let mut opts = Opts::parse();
+    let mut config = Config::load()?;
+    opts.resolve(&config)?;
+    install_tracing(
+        opts.log
+            .as_deref()
+            .or(config.log.as_deref())
+            .unwrap_or("info"),
+    )?;
+    tracing::debug!(?config, "Loaded configuration");
 
-    match opts.command.unwrap_or_default() {
+    let command = opts.command.clone().unwrap_or_default();
+    match command {
         Command::Env => {
-            let template = match shell.kind {
-                ShellKind::Zsh | ShellKind::Bash => {
-                    include_str!("../data/env.sh.j2")
-                }
+            let shell = find_shell(&opts, &config)?;
+            let template = Template::find(opts.template.as_deref(), &config, &shell.kind)?;
+            let commands = opts
+                .wrap_commands
+                .iter()
+                .chain(&config.wrap_commands)
+                .map(|name| WrappedCommand::find(name))
+                .collect::<miette::Result<Vec<_>>>()?;
 
-                ShellKind::Fish => {
-                    include_str!("../data/env.fish.j2")
+            let shell_kind = opts.shell_kind.as_ref().map(ToString::to_string);
+            let mut extra_args = Vec::new();
+            if let Some(shell_kind) = &shell_kind {
+                extra_args.extend(["--shell-kind", shell_kind]);
+            }
+            // Pass `--nom=never` along too, so that it overrides the configuration file in the
+            // wrapper.
+            if let Some(nom) = opts.nom {
+                extra_args.push(nom.as_arg());
+            }
+            // Pass the resolved flags along, including `--no-*` flags which turn off defaults
+            // from the configuration file.
+            for (enabled, default, flag, no_flag) in [
+                (opts.native(), config.native, "--native", "--no-native"),
+                (opts.cache(), config.cache, "--cache", "--no-cache"),
+                (
+                    opts.installed_nix_flags(),
+                    config.installed_nix_flags,
+                    "--installed-nix-flags",
+                    "--no-installed-nix-flags",
+                ),
+            ] {
+                if enabled {
+                    extra_args.push(flag);
+                } else if default {
+                    extra_args.push(no_flag);
                 }
+            }
+            // Pass the backend along even if it's the default, so that it overrides the
+            // configuration file and `$NIX_YOUR_SHELL_BACKEND` in the wrapper.
+            if let Some(backend) = opts.backend {
+                extra_args.extend(["--backend", backend.program()]);
+            }
 
-                ShellKind::Nushell => {
-                    include_str!("../data/env.nu.j2")
-                }
-
-                ShellKind::Xonsh => {
-                    include_str!("../data/env.xsh.j2")
-                }
-
-                ShellKind::Other(shell) => {
-                    return Err(miette!(
-                        "I don't know how to generate a shell environment for `{shell}`\n\
-                        Note: Supported shells are: `zsh`, `fish`, `nushell`, `xonsh`, and `bash`"
-                    ))
-                }
-            };
-
-            let current_exe =
-                current_exe().wrap_err("Unable to determine absolute path of `nix-your-shell`")?;
-
-            let formatted = minijinja::render!(
-                template,
-                executable => if opts.absolute || !executable_is_on_path(&current_exe)? {
-                    current_exe.as_str()
-                } else {
-                    "nix-your-shell"
-                },
-                extra_args => if opts.nom { vec!["--nom"] } else { vec![] },
+            let formatted = template.render(minijinja::context! {
+                executable => executable(opts.absolute())?,
+                extra_args => extra_args,
                 shell => shell.path.as_str(),
-            );
+                shell_kind => shell.kind.to_string(),
+                version => env!("CARGO_PKG_VERSION"),
+                nom => opts.nom.is_some_and(|nom| nom != NomMode::Never),
+                native => opts.native(),
+                nix_sourced_var => NIX_SOURCED_VAR,
+                commands => commands,
+            })?;
 
             let _ = println!("{formatted}");
             Ok(())
         }
 
         Command::NixShell { args } => {
-            let new_args = nix::transform_nix_shell(args, shell.path.as_str());
-            let prog = if opts.nom { "nom-shell" } else { "nix-shell" };
-            let command =
-                shell_words::join(std::iter::once(prog).chain(new_args.iter().map(|s| s.as_str())));
+            merge_project_config(&mut config)?;
+            let shell = find_shell(&opts, &config)?;
+            let backend = nix_shell_backend(opts.backend.unwrap_or_default());
+            let mut new_args = nix::transform_nix_shell(args, shell.path.as_str(), backend)?;
+            let subcommand_config = config.subcommand("nix-shell");
+            if let Some(subcommand_config) = subcommand_config {
+                new_args.add_flags(&subcommand_config.flags);
+                if subcommand_config.wrap == Some(false) {
+                    new_args.remove_command();
+                }
+            }
+            let nom_setting = subcommand_config.and_then(|subcommand_config| subcommand_config.nom);
+            let prog = match backend {
+                NixShellBackend::NixShell => {
+                    match nom::nom_policy(opts.nom, "nix-shell", nom_setting) {
+                        NomPolicy::Never => backend.program(),
+                        // `nix-shell` is interactive, so it can only be used with `nom-shell`.
+                        NomPolicy::Native | NomPolicy::Json => "nom-shell",
+                    }
+                }
+                NixShellBackend::CachedNixShell => {
+                    if opts.nom.is_some_and(|nom| nom != NomMode::Never) {
+                        tracing::warn!("`nom` doesn't support `cached-nix-shell`; not using `nom`");
+                    }
+                    backend.program()
+                }
+            };
+            let command = shell_words::join(
+                std::iter::once(prog).chain(new_args.args.iter().map(|s| s.as_str())),
+            );
             tracing::debug!(
                 %command,
                 "Launching nix-shell"
             );
-            Err(process::Command::new(prog)
-                .args(new_args)
-                .env(NIX_SOURCED_VAR, "1")
-                .exec())
-            .into_diagnostic()
-            .wrap_err_with(|| format!("Unable to launch {command}"))
+            let mut child = process::Command::new(prog);
+            child.args(&new_args.args).env(NIX_SOURCED_VAR, "1");
+            if new_args.command_index.is_some() {
+                child.envs(
+                    ShellInfo::from_env()
+                        .enter("nix-shell", &new_args.packages, &new_args.paths)
+                        .env_vars(),
+                );
+            }
+            Err(child.exec())
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Unable to launch {command}"))
         }
 
         Command::Nix { args } => {
-            let new_args = nix::transform_nix(args, shell.path.as_str());
-            let prog = if opts.nom
-                && new_args
-                    .subcommand
-                    .map(|subcommand| ["shell", "build", "develop"].contains(&subcommand.as_str()))
-                    .unwrap_or(false)
+            merge_project_config(&mut config)?;
+            let shell = find_shell(&opts, &config)?;
+            let nix_cli = load_nix_cli(opts.installed_nix_flags())?;
+            let mut new_args = nix::transform_nix(args, shell.path.as_str(), &nix_cli)?;
+            add_config_flags(&config, &mut new_args);
+            if config
+                .subcommand(&new_args.subcommand_path.join(" "))
+                .and_then(|subcommand_config| subcommand_config.wrap)
+                == Some(false)
             {
-                "nom"
+                new_args.remove_command();
+            }
+            let shell_info = new_args.command_index.map(|_| {
+                ShellInfo::from_env().enter(
+                    &new_args.subcommand_path.join(" "),
+                    &[],
+                    &new_args.installables,
+                )
+            });
+
+            exec_nix(&opts, &config, &shell, new_args, shell_info)
+        }
+
+        Command::PrintDevEnv { args } => {
+            merge_project_config(&mut config)?;
+            let shell = find_shell(&opts, &config)?;
+            let nix_cli = load_nix_cli(opts.installed_nix_flags())?;
+            let mut new_args = nix::transform_nix(
+                std::iter::once("develop".to_owned()).chain(args).collect(),
+                shell.path.as_str(),
+                &nix_cli,
+            )?;
+            add_config_flags(&config, &mut new_args);
+            let print_dev_env_args = new_args.print_dev_env_args().ok_or_else(|| {
+                miette!("Arguments to `print-dev-env` cannot include a command to run")
+            })?;
+            let dev_env = load_dev_env(&opts, &new_args, &print_dev_env_args)?;
+            let _ = println!("{}", dev_env.render(&shell.kind)?);
+            Ok(())
+        }
+
+        Command::Enter {
+            develop,
+            packages,
+            installables,
+        } => {
+            merge_project_config(&mut config)?;
+            let shell = find_shell(&opts, &config)?;
+            let installables: Vec<_> = packages
+                .iter()
+                .map(|package| format!("nixpkgs#{package}"))
+                .chain(installables)
+                .collect();
+            if develop.is_empty() && installables.is_empty() {
+                return Err(miette!(
+                    "Nothing to enter\n\
+                    Note: Give installables, `--package`s, or `--develop` shells to enter"
+                ));
+            }
+            let nix_cli = load_nix_cli(opts.installed_nix_flags())?;
+            let new_args = nix::enter_nix(
+                &develop,
+                &installables,
+                shell.path.as_str(),
+                &nix_cli,
+                |args| add_config_flags(&config, args),
+            )?;
+            if opts.native() && new_args.print_dev_env_args().is_none() {
+                tracing::warn!(
+                    "`--native` only applies when entering a single `--develop` shell without \
+                    packages or installables; running `nix` instead"
+                );
+            }
+            // `packages` are at the front of `installables`.
+            let subcommand = if installables.is_empty() {
+                "develop"
             } else {
-                "nix"
+                "shell"
+            };
+            let shell_info = ShellInfo::from_env().enter(
+                subcommand,
+                &packages,
+                &[&develop[..], &installables[packages.len()..]].concat(),
+            );
+            exec_nix(&opts, &config, &shell, new_args, Some(shell_info))
+        }
+
+        Command::Info => {
+            let shell = find_shell(&opts, &config)?;
+            let _ = println!("{}", prompt::prompt_segment(&shell.kind)?);
+            Ok(())
+        }
+
+        Command::Prompt { format } => {
+            let rendered = ShellInfo::from_env().render(format, &executable(opts.absolute())?)?;
+            if !rendered.is_empty() {
+                let _ = stdoutln!("{rendered}");
+            }
+            Ok(())
+        }
+
+        Command::Wrapped(args) => {
+            let (name, args) = args
+                .split_first()
+                .ok_or_else(|| miette!("No command to wrap"))?;
+            let wrapped = WrappedCommand::get(name).ok_or_else(|| {
+                miette!(
+                    "Unknown command `{name}`\n\
+                    Note: See `nix-your-shell --help` for commands; commands which can be \
+                    wrapped are: {}",
+                    WrappedCommand::names()
+                )
+            })?;
+            merge_project_config(&mut config)?;
+            let shell = find_shell(&opts, &config)?;
+            // Arguments are passed after a `--` so they aren't parsed as our own flags.
+            let args = match args.split_first() {
+                Some((first, rest)) if first == "--" => rest.to_vec(),
+                _ => args.to_vec(),
             };
+            let nix_cli = load_nix_cli(opts.installed_nix_flags())?;
+            let new_args = wrapped.transform(args, shell.path.as_str(), &nix_cli)?;
+            let mut program = new_args.program;
+            // `nom-build` takes the same arguments as `nix-build`, but it can't start a shell.
+            if name == "nix-build" && new_args.shell_info.is_none() {
+                let nom_setting = config
+                    .subcommand(name)
+                    .and_then(|subcommand_config| subcommand_config.nom);
+                if nom::nom_policy(opts.nom, name, nom_setting) != NomPolicy::Never {
+                    program = nom::program(name);
+                }
+            }
             let command = shell_words::join(
-                std::iter::once(prog).chain(new_args.args.iter().map(|s| s.as_str())),
+                std::iter::once(program).chain(new_args.args.iter().map(|s| s.as_str())),
             );
-            tracing::debug!(%command, "Launching nix");
-            Err(process::Command::new(prog)
-                .args(new_args.args)
+            tracing::debug!(%command, "Launching wrapped command");
+            let mut child = process::Command::new(program);
+            child.args(&new_args.args).env(NIX_SOURCED_VAR, "1");
+            if let Some(shell_info) = new_args.shell_info {
+                child.envs(shell_info.env_vars());
+            }
+            Err(child.exec())
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Unable to launch {command}"))
+        }
+
+        Command::Allow { path } => allow(path.as_deref()),
+
+        Command::Deny { path } => deny(path.as_deref()),
+
+        Command::Cache { command } => cache(&command),
+    }
+}
+
+/// Find the shell to use, from the command line, the configuration file, or the environment.
+fn find_shell(opts: &Opts, config: &Config) -> miette::Result<Shell> {
+    Ok(
+        match (
+            opts.shell.as_deref().or(config.shell.as_deref()),
+            &opts.shell_kind,
+        ) {
+            (Some(input), Some(kind)) => Shell::with_kind(input, kind.clone()),
+            (Some(input), None) => {
+                let shell = Shell::from_path(input)?;
+                tracing::debug!(%shell, input, "Detected shell");
+                shell
+            }
+            (None, kind) => Shell::detect(kind.as_ref())?,
+        },
+    )
+}
+
+/// Merge the current project's configuration into `config`, if there is one and it's allowed.
+fn merge_project_config(config: &mut Config) -> miette::Result<()> {
+    if let Some(project) = ProjectConfig::discover()? {
+        config.merge(project);
+    }
+    Ok(())
+}
+
+/// Launch `nix` with the given arguments, or the shell directly if entering a development shell
+/// natively.
+///
+/// If `nix` launches the shell, `shell_info` describes the shell for prompts.
+fn exec_nix(
+    opts: &Opts,
+    config: &Config,
+    shell: &Shell,
+    new_args: nix::NixArgs,
+    shell_info: Option<ShellInfo>,
+) -> miette::Result<()> {
+    let shell_env = shell_info
+        .map(|shell_info| shell_info.env_vars().to_vec())
+        .unwrap_or_default();
+
+    if opts.native() {
+        if let Some(print_dev_env_args) = new_args.print_dev_env_args() {
+            let dev_env = load_dev_env(opts, &new_args, &print_dev_env_args)?;
+            tracing::debug!(%shell, "Launching shell in native development environment");
+            return Err(process::Command::new(shell.path.as_str())
+                .envs(dev_env.exported())
+                .envs(shell_env)
                 .env(NIX_SOURCED_VAR, "1")
                 .exec())
             .into_diagnostic()
-            .wrap_err_with(|| format!("Unable to launch {command}"))
+            .wrap_err_with(|| format!("Unable to launch {}", shell.path));
+        }
+    }
+
+    let subcommand = new_args.subcommand_path.join(" ");
+    let nom_setting = config
+        .subcommand(&subcommand)
+        .and_then(|subcommand_config| subcommand_config.nom);
+    let prog = match nom::nom_policy(opts.nom, &subcommand, nom_setting) {
+        NomPolicy::Native => "nom",
+        NomPolicy::Json => return run_nom_json(new_args, shell_env),
+        NomPolicy::Never => "nix",
+    };
+    let command =
+        shell_words::join(std::iter::once(prog).chain(new_args.args.iter().map(|s| s.as_str())));
+    tracing::debug!(%command, "Launching nix");
+    Err(process::Command::new(prog)
+        .args(new_args.args)
+        .envs(shell_env)
+        .env(NIX_SOURCED_VAR, "1")
+        .exec())
+    .into_diagnostic()
+    .wrap_err_with(|| format!("Unable to launch {command}"))
+}
+
+/// Evaluate the development environment for a `nix develop` invocation, using the cache if
+/// enabled.
+fn load_dev_env(
+    opts: &Opts,
+    new_args: &nix::NixArgs,
+    print_dev_env_args: &[String],
+) -> miette::Result<DevEnv> {
+    if opts.cache() {
+        DevEnvCache::open()?.dev_env(
+            print_dev_env_args,
+            new_args.installables.first().map(String::as_str),
+        )
+    } else {
+        DevEnv::from_nix(print_dev_env_args)
+    }
+}
+
+/// Manage cached development environments.
+fn cache(command: &CacheCommand) -> miette::Result<()> {
+    let cache = DevEnvCache::open()?;
+    match command {
+        CacheCommand::List => {
+            for (key, entry) in cache.entries()? {
+                let status = if entry.is_stale() { "stale" } else { "fresh" };
+                let _ = stdoutln!(
+                    "{key} {}: nix {} ({status})",
+                    entry.flake_dir,
+                    shell_words::join(&entry.args),
+                );
+            }
+        }
+        CacheCommand::Clear => {
+            cache.clear()?;
+            tracing::info!("Removed all cached development environments");
+        }
+        CacheCommand::Gc => {
+            for (key, entry) in cache.entries()? {
+                if entry.is_stale() {
+                    cache.remove(&key)?;
+                    tracing::info!(
+                        "Removed stale development environment for {}",
+                        entry.flake_dir
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Add the flags from the configuration file to a `nix` invocation: the global `nix-flags`, then
+/// the subcommand's `flags` and `default-installable`.
+fn add_config_flags(config: &Config, args: &mut nix::NixArgs) {
+    let mut flags = config.nix_flags.clone();
+    if let Some(subcommand_config) = config.subcommand(&args.subcommand_path.join(" ")) {
+        flags.extend_from_slice(&subcommand_config.flags);
+        if let Some(installable) = &subcommand_config.default_installable {
+            if args.installables.is_empty() {
+                flags.push(installable.clone());
+                args.installables.push(installable.clone());
+            }
         }
     }
+    args.add_flags(&flags);
+}
+
+/// Find the project configuration file to `allow` or `deny`.
+fn project_config_path(path: Option<&Utf8Path>) -> miette::Result<Utf8PathBuf> {
+    match path {
+        Some(path) => Ok(path.to_owned()),
+        None => config::find_project_config()?.ok_or_else(|| {
+            miette!(
+                "No `{}` found in this directory or its parents",
+                config::PROJECT_CONFIG_FILE
+            )
+        }),
+    }
+}
+
+/// Allow a project configuration file.
+fn allow(path: Option<&Utf8Path>) -> miette::Result<()> {
+    let path = project_config_path(path)?;
+    // Make sure the file is valid before allowing it, and allow the contents we checked.
+    let contents = config::read(&path)?;
+    config::parse_str::<ProjectConfig>(path.clone(), contents.clone())?;
+    let mut allow_list = AllowList::load()?;
+    allow_list.allow(&path, contents.as_bytes())?;
+    allow_list.save()?;
+    tracing::info!("Allowed {path}");
+    Ok(())
+}
+
+/// Stop allowing a project configuration file.
+fn deny(path: Option<&Utf8Path>) -> miette::Result<()> {
+    let path = project_config_path(path)?;
+    let mut allow_list = AllowList::load()?;
+    if allow_list.deny(&path) {
+        allow_list.save()?;
+        tracing::info!("Denied {path}");
+    } else {
+        tracing::info!("{path} was not allowed");
+    }
+    Ok(())
+}
+
+/// Run `nix` with JSON logs, piping them into `nom --json`, for subcommands `nom` doesn't support
+/// directly.
+///
+/// The user's flags are kept, including verbosity flags like `-v`, except that `--log-format` is
+/// always `internal-json`.
+///
+/// Exits with `nix`'s exit code.
+fn run_nom_json(mut new_args: nix::NixArgs, shell_env: Vec<(&str, String)>) -> miette::Result<()> {
+    new_args.set_log_format("internal-json");
+    let command =
+        shell_words::join(std::iter::once("nix").chain(new_args.args.iter().map(|s| s.as_str())));
+    tracing::debug!(%command, "Launching nix with nom --json");
+
+    let mut nom = process::Command::new("nom")
+        .arg("--json")
+        .stdin(process::Stdio::piped())
+        .spawn()
+        .into_diagnostic()
+        .wrap_err("Unable to launch nom --json")?;
+    let nom_stdin = nom.stdin.take().expect("nom's stdin is piped");
+    let status = process::Command::new("nix")
+        .args(&new_args.args)
+        .envs(shell_env)
+        .env(NIX_SOURCED_VAR, "1")
+        .stderr(nom_stdin)
+        .status()
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Unable to launch {command}"));
+    // Wait for `nom` to print its summary, even if `nix` failed to launch.
+    let nom_status = nom.wait().into_diagnostic();
+    let status = status?;
+    if let Ok(nom_status) = nom_status {
+        if !nom_status.success() {
+            tracing::debug!(%nom_status, "nom --json failed");
+        }
+    }
+    process::exit(status.code().unwrap_or(1))
+}
+
+/// Use the given `nix-shell` backend if it's installed, or fall back to `nix-shell`.
+fn nix_shell_backend(backend: NixShellBackend) -> NixShellBackend {
+    if backend != NixShellBackend::NixShell && find_executable(backend.program()).is_none() {
+        tracing::warn!(
+            "Unable to find `{}` on the `$PATH`; using `nix-shell` instead",
+            backend.program()
+        );
+        return NixShellBackend::NixShell;
+    }
+    backend
+}
+
+/// Load `nix`'s flags, either from the installed `nix` or from the built-in snapshot.
+///
+/// Falls back to the built-in snapshot if the installed `nix`'s flags can't be read.
+fn load_nix_cli(installed: bool) -> miette::Result<NixCli> {
+    if installed {
+        match find_executable("nix") {
+            Some(nix) => match NixCli::installed(&nix) {
+                Ok(nix_cli) => return Ok(nix_cli),
+                Err(err) => {
+                    tracing::warn!("Failed to read flags from the installed `nix`: {err:?}");
+                }
+            },
+            None => {
+                tracing::warn!("Unable to find `nix` on the `$PATH`; using built-in flags");
+            }
+        }
+    }
+    NixCli::snapshot()
 }
 
 fn install_tracing(filter_directives: &str) -> miette::Result<()> {
@@ -196,6 +927,24 @@ fn install_tracing(filter_directives: &str) -> miette::Result<()> {
     Ok(())
 }
 
+/// Read a boolean flag from an environment variable, like `NIX_YOUR_SHELL_NATIVE=1`.
+///
+/// Empty variables are treated as unset.
+fn env_flag(var: &str) -> miette::Result<Option<bool>> {
+    let Some(value) = std::env::var_os(var).filter(|value| !value.is_empty()) else {
+        return Ok(None);
+    };
+    BoolishValueParser::new()
+        .parse_ref(&Opts::command(), None, &value)
+        .map(Some)
+        .map_err(|_| {
+            miette!(
+                "Invalid value for ${var}: {value:?}\n\
+                Note: Use a boolean like `true`, `false`, `1`, or `0`"
+            )
+        })
+}
+
 /// Get the path to the current executable.
 fn current_exe() -> miette::Result<Utf8PathBuf> {
     Utf8PathBuf::from_path_buf(
@@ -206,6 +955,19 @@ fn current_exe() -> miette::Result<Utf8PathBuf> {
     .map_err(|path_buf| miette!("Path is not UTF-8: {path_buf:?}"))
 }
 
+/// The `nix-your-shell` executable to call from generated code and configuration.
+///
+/// This is just `nix-your-shell` if it's on the `$PATH`, unless `absolute` is set.
+fn executable(absolute: bool) -> miette::Result<String> {
+    let current_exe =
+        current_exe().wrap_err("Unable to determine absolute path of `nix-your-shell`")?;
+    if absolute || !executable_is_on_path(&current_exe)? {
+        Ok(current_exe.into_string())
+    } else {
+        Ok("nix-your-shell".to_owned())
+    }
+}
+
 fn executable_is_on_path(executable: &Utf8Path) -> miette::Result<bool> {
     let directory = executable
         .parent()
@@ -218,3 +980,52 @@ fn executable_is_on_path(executable: &Utf8Path) -> miette::Result<bool> {
         .map(Utf8Path::new)
         .any(|component| component == directory))
 }
+
+/// Find an executable on the `$PATH`.
+fn find_executable(name: &str) -> Option<Utf8PathBuf> {
+    let path = std::env::var("PATH").ok()?;
+    path.split(':')
+        .filter(|component| !component.is_empty())
+        .map(|component| Utf8Path::new(component).join(name))
+        .find(|candidate| {
+            candidate
+                .metadata()
+                .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+                .unwrap_or(false)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(args: &[&str], config: &Config) -> bool {
+        let mut opts = Opts::try_parse_from(
+            std::iter::once("nix-your-shell")
+                .chain(args.iter().copied())
+                .chain(["bash"]),
+        )
+        .unwrap();
+        opts.resolve(config).unwrap();
+        opts.cache()
+    }
+
+    #[test]
+    fn test_flag_precedence() {
+        let config = Config {
+            cache: true,
+            ..Default::default()
+        };
+        assert!(cache(&[], &config));
+        assert!(!cache(&["--no-cache"], &config));
+        assert!(!cache(&["--cache=false"], &config));
+        assert!(cache(&["--no-cache", "--cache"], &config));
+        assert!(!cache(&["--cache", "--no-cache"], &config));
+
+        let config = Config::default();
+        assert!(!cache(&[], &config));
+        assert!(cache(&["--cache"], &config));
+        assert!(cache(&["--cache=yes"], &config));
+        assert!(!cache(&["--cache", "--no-cache"], &config));
+    }
+}
@@ -1,256 +1,278 @@
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+
+use miette::SourceSpan;
+
+use crate::nix_cli::NixCli;
+
+/// A flag was given without all of the arguments it takes, like `nix develop --arg foo`.
+#[derive(Debug, thiserror::Error, miette::Diagnostic)]
+#[error("`{flag}` expects {}, but {}", arguments(*.expected), given(*.found))]
+pub struct MissingFlagArguments {
+    flag: String,
+    expected: usize,
+    found: usize,
+    /// The reconstructed command line.
+    #[source_code]
+    command_line: String,
+    /// The span of the flag and the arguments that were given for it.
+    #[label("expects {}", arguments(*.expected))]
+    span: SourceSpan,
+}
+
+impl MissingFlagArguments {
+    fn new(program: &str, args: &[String], index: usize, expected: usize) -> Self {
+        let words = || std::iter::once(program).chain(args.iter().map(|s| s.as_str()));
+        let command_line = shell_words::join(words());
+        let offset = shell_words::join(words().take(index + 1)).len() + 1;
+        Self {
+            flag: args[index].clone(),
+            expected,
+            found: args.len() - index - 1,
+            span: (offset, command_line.len() - offset).into(),
+            command_line,
+        }
+    }
+}
+
+fn arguments(count: usize) -> String {
+    match count {
+        1 => "1 argument".to_owned(),
+        _ => format!("{count} arguments"),
+    }
+}
+
+fn given(count: usize) -> String {
+    match count {
+        0 => "none were given".to_owned(),
+        1 => "only 1 was given".to_owned(),
+        _ => format!("only {count} were given"),
+    }
+}
+
+/// Get the `arity` arguments to the flag at `args[index]`.
+fn flag_arguments<'a>(
+    program: &str,
+    args: &'a [String],
+    index: usize,
+    arity: usize,
+) -> Result<&'a [String], MissingFlagArguments> {
+    args.get(index + 1..index + 1 + arity)
+        .ok_or_else(|| MissingFlagArguments::new(program, args, index, arity))
+}
+
 /// Arguments to a `nix` invocation.
 #[derive(Debug)]
 pub struct NixArgs {
     /// Arguments to the `nix` invocation, including the subcommand.
     pub args: Vec<String>,
-    /// Subcommand to run, like `build` or `shell`.
-    pub subcommand: Option<String>,
+    /// The full path of the subcommand to run, like `["profile", "install"]`.
+    pub subcommand_path: Vec<String>,
+    /// Index of the last component of `subcommand_path` in `args`.
+    pub subcommand_index: Option<usize>,
+    /// Index of the `--command` argument we added to `args`, if any.
+    pub command_index: Option<usize>,
+    /// Positional arguments to the subcommand, like `nixpkgs#hello`.
+    pub installables: Vec<String>,
+}
+
+/// Arguments to a `nix-shell` invocation.
+#[derive(Debug)]
+pub struct NixShellArgs {
+    /// Arguments to the `nix-shell` invocation.
+    pub args: Vec<String>,
+    /// Index of the `--command` argument we added to `args`, if any.
+    pub command_index: Option<usize>,
+    /// Packages given with `--packages`, like `hello`.
+    pub packages: Vec<String>,
+    /// Other positional arguments, like `shell.nix`.
+    pub paths: Vec<String>,
+    /// Is `nix-shell` running a script in shebang mode?
+    pub shebang: bool,
+}
+
+/// Flags which `nix develop` takes but `nix print-dev-env` doesn't, because they change how the
+/// shell is started rather than which environment is evaluated.
+const DEVELOP_ONLY_FLAGS: &[&str] = &[
+    "-i",
+    "--ignore-environment",
+    "-k",
+    "--keep",
+    "--keep-env-var",
+    "-u",
+    "--unset",
+    "--unset-env-var",
+    "-s",
+    "--set-env-var",
+    "--phase",
+    "--build",
+    "--check",
+    "--configure",
+    "--install",
+    "--installcheck",
+    "--unpack",
+    "--derivation",
+];
+
+impl NixArgs {
+    /// Arguments to a `nix print-dev-env --json` invocation which evaluates the same environment
+    /// as this `nix develop` invocation.
+    ///
+    /// Returns `None` if this isn't a `nix develop` invocation, if the user has already given a
+    /// command to run, or if the user gave a flag `nix print-dev-env` doesn't take (like
+    /// `--ignore-environment`). `--profile` isn't supported either, because the development
+    /// environment cache uses its own profile.
+    pub fn print_dev_env_args(&self) -> Option<Vec<String>> {
+        if self.subcommand_path != ["develop"] {
+            return None;
+        }
+        let subcommand_index = self.subcommand_index?;
+        let command_index = self.command_index?;
+        if let Some(flag) = self.args[..command_index]
+            .iter()
+            .find(|arg| *arg == "--profile" || DEVELOP_ONLY_FLAGS.contains(&arg.as_str()))
+        {
+            tracing::debug!(flag, "`nix print-dev-env` doesn't support flag");
+            return None;
+        }
+
+        let mut args = self.args.clone();
+        args.drain(command_index..command_index + 2);
+        args[subcommand_index] = "print-dev-env".into();
+        args.insert(subcommand_index + 1, "--json".into());
+        Some(args)
+    }
+
+    /// Add `flags` after the subcommand, or at the start if there's no subcommand.
+    pub fn add_flags(&mut self, flags: &[String]) {
+        let index = self.subcommand_index.map(|index| index + 1).unwrap_or(0);
+        self.args.splice(index..index, flags.iter().cloned());
+        if let Some(command_index) = &mut self.command_index {
+            *command_index += flags.len();
+        }
+    }
+
+    /// Remove the `--command` argument we added, if any.
+    pub fn remove_command(&mut self) {
+        if let Some(command_index) = self.command_index.take() {
+            self.args.drain(command_index..command_index + 2);
+        }
+    }
+
+    /// Set `--log-format`, replacing the format the user gave, if any. Other flags, like `-v`,
+    /// are left alone.
+    pub fn set_log_format(&mut self, format: &str) {
+        let end = self
+            .args
+            .iter()
+            .position(|arg| arg == "--")
+            .unwrap_or(self.args.len());
+        let given = self.args[..end]
+            .iter()
+            .rposition(|arg| arg == "--log-format")
+            .filter(|index| index + 1 < end);
+        match given {
+            Some(index) => self.args[index + 1] = format.to_owned(),
+            None => self.add_flags(&["--log-format".to_owned(), format.to_owned()]),
+        }
+    }
+}
+
+impl NixShellArgs {
+    /// Add `flags` at the start of the arguments.
+    ///
+    /// Flags aren't added if `nix-shell` is running a script, because the script has to come
+    /// first.
+    pub fn add_flags(&mut self, flags: &[String]) {
+        if self.shebang {
+            return;
+        }
+        self.args.splice(0..0, flags.iter().cloned());
+        if let Some(command_index) = &mut self.command_index {
+            *command_index += flags.len();
+        }
+    }
+
+    /// Remove the `--command` argument we added, if any.
+    pub fn remove_command(&mut self) {
+        if let Some(command_index) = self.command_index.take() {
+            self.args.drain(command_index..command_index + 2);
+        }
+    }
 }
 
 /// Transform arguments to a `nix` invocation to run the specified `command`.
 ///
-/// Only modifies `nix develop` and `nix shell` commands.
-pub fn transform_nix(args: Vec<String>, command: &str) -> NixArgs {
+/// Only modifies `nix develop`, `nix shell`, and `nix env shell` commands.
+///
+/// Flags and subcommands are recognized using the given `cli`.
+pub fn transform_nix(
+    args: Vec<String>,
+    command: &str,
+    cli: &NixCli,
+) -> Result<NixArgs, MissingFlagArguments> {
     let mut ret = Vec::with_capacity(args.len() + 2);
 
-    let mut subcommand = None;
+    let mut subcommand_path = Vec::new();
+    let mut subcommand_index = None;
+    let mut installables = Vec::new();
+    // Positional arguments after a `--`, if any. These are passed through as they are.
+    let mut trailing: Option<&[String]> = None;
 
     let mut i = 0;
     while i < args.len() {
+        if args[i] == "--" {
+            // End of options; everything after this is a positional argument, even if it looks
+            // like a flag or a subcommand.
+            trailing = Some(&args[i + 1..]);
+            if !subcommand_path.is_empty() {
+                installables.extend_from_slice(&args[i + 1..]);
+            }
+            break;
+        }
+
         ret.push(args[i].clone());
 
         match args[i].as_str() {
-            "--help" | "--version"
-                | "-c" | "--command"
-                => {
+            "--help" | "--version" | "-c" | "--command" => {
                 // We already have a command to run.
-                return NixArgs {
-                        args,
-                        subcommand
-                    };
+                return Ok(NixArgs {
+                    args,
+                    subcommand_path,
+                    subcommand_index,
+                    command_index: None,
+                    installables,
+                });
             }
 
-            // Two arguments
-            "--option"
-                | "--redirect"
-                | "--override-flake"
-                | "--arg"
-                | "--argstr"
-                | "--override-input"
-                => {
-                ret.push(args[i + 1].clone());
-                ret.push(args[i + 2].clone());
-                i += 2;
+            flag if flag.starts_with('-') && flag != "-" => {
+                // The same flag can take different numbers of arguments in different subcommands
+                // (like `-s`, which is `--set-env-var NAME VALUE` in `nix develop` but
+                // `--substitute-on-destination` in `nix copy`), so look it up in the subcommand
+                // we've seen so far.
+                let arity = cli.arity(&subcommand_path, flag).unwrap_or_else(|| {
+                    tracing::warn!(
+                        "Unknown `nix` flag `{flag}`; assuming it takes no arguments \
+                        (use `--installed-nix-flags` to read flags from the installed `nix`)"
+                    );
+                    0
+                });
+                ret.extend_from_slice(flag_arguments("nix", &args, i, arity)?);
+                i += arity;
             }
 
-            // One argument
-            "--log-format"
-            | "--access-tokens"
-            | "--allowed-impure-host-deps"
-            | "--allowed-uris"
-            | "--allowed-users"
-            | "--bash-prompt"
-            | "--bash-prompt-prefix"
-            | "--bash-prompt-suffix"
-            | "--build-hook"
-            | "--build-poll-interval"
-            | "--build-users-group"
-            | "--builders"
-            | "--commit-lockfile-summary"
-            | "--connect-timeout"
-            | "--cores"
-            | "--diff-hook"
-            | "--download-attempts"
-            | "--download-speed"
-            | "--experimental-features"
-            | "--extra-access-tokens"
-            | "--extra-allowed-impure-host-deps"
-            | "--extra-allowed-uris"
-            | "--extra-allowed-users"
-            | "--extra-experimental-features"
-            | "--extra-extra-platforms"
-            | "--extra-hashed-mirrors"
-            | "--extra-nix-path"
-            | "--extra-platforms"
-            | "--extra-plugin-files"
-            | "--extra-sandbox-paths"
-            | "--extra-secret-key-files"
-            | "--extra-substituters"
-            | "--extra-system-features"
-            | "--extra-trusted-public-keys"
-            | "--extra-trusted-substituters"
-            | "--extra-trusted-users"
-            | "--flake-registry"
-            | "--gc-reserved-space"
-            | "--hashed-mirrors"
-            | "--http-connections"
-            | "--log-lines"
-            | "--max-build-log-size"
-            | "--max-free"
-            | "--max-jobs"
-            | "--max-silent-time"
-            | "--min-free"
-            | "--min-free-check-interval"
-            | "--nar-buffer-size"
-            | "--narinfo-cache-negative-ttl"
-            | "--narinfo-cache-positive-ttl"
-            | "--netrc-file"
-            | "--nix-path"
-            | "--plugin-files"
-            | "--post-build-hook"
-            | "--pre-build-hook"
-            | "--repeat"
-            | "--sandbox-paths"
-            | "--secret-key-files"
-            | "--stalled-download-timeout"
-            | "--store"
-            | "--substituters"
-            | "--system"
-            | "--system-features"
-            | "--tarball-ttl"
-            | "--timeout"
-            | "--trusted-public-keys"
-            | "--trusted-substituters"
-            | "--trusted-users"
-            | "--user-agent-suffix"
-            // `nix develop` options
-            | "-k" | "--keep"
-            | "--phase"
-            |"--profile"
-            | "--unset"
-            | "--eval-store"
-            | "-I" | "--include"
-            | "--inputs-from"
-            | "--update-input"
-            | "--expr"
-            | "-f" | "--file"
-            => {
-                ret.push(args[i + 1].clone());
-                i += 1;
+            // A subcommand, or a nested subcommand like the `install` in `nix profile install`.
+            name if cli.is_subcommand(&subcommand_path, name) => {
+                subcommand_path.push(name.to_owned());
+                subcommand_index = Some(ret.len() - 1);
             }
 
-            // Zero arguments
-            "--offline"
-            | "--refresh"
-            | "--debug"
-            | "-L"
-            | "--print-build-logs"
-            | "--quiet"
-            | "-v"
-            | "--verbose"
-            | "--accept-flake-config"
-            | "--no-accept-flake-config"
-            | "--allow-dirty"
-            | "--no-allow-dirty"
-            | "--allow-import-from-derivation"
-            | "--no-allow-import-from-derivation"
-            | "--allow-symlinked-store"
-            | "--no-allow-symlinked-store"
-            | "--allow-unsafe-native-code-during-evaluation"
-            | "--no-allow-unsafe-native-code-during-evaluation"
-            | "--auto-optimise-store"
-            | "--no-auto-optimise-store"
-            | "--builders-use-substitutes"
-            | "--no-builders-use-substitutes"
-            | "--compress-build-log"
-            | "--no-compress-build-log"
-            | "--darwin-log-sandbox-violations"
-            | "--no-darwin-log-sandbox-violations"
-            | "--enforce-determinism"
-            | "--no-enforce-determinism"
-            | "--eval-cache"
-            | "--no-eval-cache"
-            | "--fallback"
-            | "--no-fallback"
-            | "--fsync-metadata"
-            | "--no-fsync-metadata"
-            | "--http2"
-            | "--no-http2"
-            | "--ignore-try"
-            | "--no-ignore-try"
-            | "--impersonate-linux-26"
-            | "--no-impersonate-linux-26"
-            | "--keep-build-log"
-            | "--no-keep-build-log"
-            | "--keep-derivations"
-            | "--no-keep-derivations"
-            | "--keep-env-derivations"
-            | "--no-keep-env-derivations"
-            | "--keep-failed"
-            | "--no-keep-failed"
-            | "--keep-going"
-            | "--no-keep-going"
-            | "--keep-outputs"
-            | "--no-keep-outputs"
-            | "--preallocate-contents"
-            | "--no-preallocate-contents"
-            | "--print-missing"
-            | "--no-print-missing"
-            | "--pure-eval"
-            | "--no-pure-eval"
-            | "--require-sigs"
-            | "--no-require-sigs"
-            | "--restrict-eval"
-            | "--no-restrict-eval"
-            | "--run-diff-hook"
-            | "--no-run-diff-hook"
-            | "--sandbox"
-            | "--no-sandbox"
-            | "--sandbox-fallback"
-            | "--no-sandbox-fallback"
-            | "--show-trace"
-            | "--no-show-trace"
-            | "--substitute"
-            | "--no-substitute"
-            | "--sync-before-registering"
-            | "--no-sync-before-registering"
-            | "--trace-function-calls"
-            | "--no-trace-function-calls"
-            | "--trace-verbose"
-            | "--no-trace-verbose"
-            | "--use-case-hack"
-            | "--no-use-case-hack"
-            | "--use-registries"
-            | "--no-use-registries"
-            | "--use-sqlite-wal"
-            | "--no-use-sqlite-wal"
-            | "--warn-dirty"
-            | "--no-warn-dirty"
-            | "--relaxed-sandbox"
-            // `nix develop` options
-            | "--build"
-            | "--check"
-            | "--configure"
-            | "--debugger"
-            | "-i" | "--ignore-environment"
-            | "--install"
-            | "--installcheck"
-            | "--unpack"
-            | "--impure"
-            | "--commit-lock-file"
-            | "--no-registries"
-            | "--no-update-lock-file"
-            | "--no-write-lock-file"
-            | "--recreate-lock-file"
-            | "--derivation"
-            => {}
-
-            "build" | "develop" | "flake" | "help" | "profile" | "repl" | "run" | "search"
-            | "shell" | "bundle" | "copy" | "edit" | "eval" | "fmt" | "log" | "path-info"
-            | "registry" | "why-depends" | "daemon" | "describe-stores" | "hash" | "key"
-            | "nar" | "print-dev-env" | "realisation" | "show-config" | "show-derivation"
-            | "store" | "doctor" | "upgrade-nix" => {
-                // Top-level subcommand.
-
-                // Replace `subcommand` unless it already has a value.
-                if subcommand.is_none() {
-                    subcommand = Some(args[i].clone());
+            positional => {
+                if !subcommand_path.is_empty() {
+                    installables.push(positional.to_owned());
                 }
             }
-
-            _ => {
-                // Unknown argument, ignore.
-            }
         }
 
         i += 1;
@@ -261,102 +283,627 @@ pub fn transform_nix(args: Vec<String>, command: &str) -> NixArgs {
     //
     // Note that this behavior is unlike `nix-shell`, where the `--command` flag takes one argument
     // that may include spaces...
-    match subcommand.as_deref() {
-        Some("develop") | Some("shell") => {
-            ret.push("--command".into());
-            ret.push(command.into());
+    //
+    // `--command` can't come after a `--`, where it would be a positional argument. Positional
+    // arguments after a `--` only need to be there if they look like flags, so we move the rest
+    // before our `--command`.
+    let path: Vec<_> = subcommand_path.iter().map(|s| s.as_str()).collect();
+    let command_index = match path.as_slice() {
+        ["develop"] | ["shell"] | ["env", "shell"] => {
+            let trailing = trailing.unwrap_or_default();
+            if let Some(arg) = trailing.iter().find(|arg| arg.starts_with('-')) {
+                tracing::warn!(
+                    "Not adding `--command {command}` because `{arg}` has to come after `--`, \
+                    where `--command` would be treated as a positional argument"
+                );
+                ret.push("--".into());
+                ret.extend_from_slice(trailing);
+                None
+            } else {
+                ret.extend_from_slice(trailing);
+                let index = ret.len();
+                ret.push("--command".into());
+                ret.push(command.into());
+                Some(index)
+            }
         }
 
-        _ => {}
-    }
+        _ => {
+            if let Some(trailing) = trailing {
+                ret.push("--".into());
+                ret.extend_from_slice(trailing);
+            }
+            None
+        }
+    };
 
-    NixArgs {
+    Ok(NixArgs {
         args: ret,
-        subcommand,
+        subcommand_path,
+        subcommand_index,
+        command_index,
+        installables,
+    })
+}
+
+/// Arguments to a `nix` invocation which enters each of the `dev_shells` with `nix develop` and
+/// then a `nix shell` with the `installables`, running the specified `command` at the end.
+///
+/// The invocations are chained together with `--command nix ...`, and `configure` is called on
+/// each invocation before it's chained, innermost first.
+pub fn enter_nix(
+    dev_shells: &[String],
+    installables: &[String],
+    command: &str,
+    cli: &NixCli,
+    configure: impl Fn(&mut NixArgs),
+) -> Result<NixArgs, MissingFlagArguments> {
+    let mut invocations: Vec<Vec<String>> = dev_shells
+        .iter()
+        .map(|dev_shell| vec!["develop".to_owned(), dev_shell.clone()])
+        .collect();
+    if !installables.is_empty() || invocations.is_empty() {
+        invocations.push(
+            std::iter::once("shell".to_owned())
+                .chain(installables.iter().cloned())
+                .collect(),
+        );
+    }
+
+    let mut invocations = invocations.into_iter().rev();
+    let innermost = invocations
+        .next()
+        .expect("There is always at least one invocation");
+    let mut ret = transform_nix(innermost, command, cli)?;
+    configure(&mut ret);
+    for mut invocation in invocations {
+        invocation.push("--command".to_owned());
+        invocation.push("nix".to_owned());
+        invocation.extend(ret.args);
+        ret = transform_nix(invocation, command, cli)?;
+        configure(&mut ret);
     }
+    Ok(ret)
+}
+
+/// The program to run `nix-shell` invocations with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NixShellBackend {
+    /// `nix-shell` itself.
+    #[default]
+    NixShell,
+    /// `cached-nix-shell`, which caches evaluations of `nix-shell` environments.
+    ///
+    /// See: <https://github.com/xzfc/cached-nix-shell>
+    CachedNixShell,
+}
+
+impl NixShellBackend {
+    /// The name of the backend's executable.
+    pub fn program(self) -> &'static str {
+        match self {
+            NixShellBackend::NixShell => "nix-shell",
+            NixShellBackend::CachedNixShell => "cached-nix-shell",
+        }
+    }
+
+    /// How the backend treats a flag, or `None` for unknown flags.
+    fn flag(self, flag: &str) -> Option<NixShellFlag> {
+        match self {
+            NixShellBackend::NixShell => nix_shell_flag(flag),
+            NixShellBackend::CachedNixShell => cached_nix_shell_flag(flag),
+        }
+    }
+}
+
+/// How `nix-shell` or `cached-nix-shell` treats a flag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NixShellFlag {
+    /// The flag takes this many arguments.
+    Arguments(usize),
+    /// `--packages`, which makes positional arguments packages.
+    Packages,
+    /// The flag gives a command to run (or doesn't start a shell at all), so we shouldn't add
+    /// our own `--command`.
+    Command,
+}
+
+/// How `nix-shell` treats a flag.
+///
+/// See: <https://github.com/NixOS/nix/blob/master/src/nix-build/nix-build.cc>
+fn nix_shell_flag(flag: &str) -> Option<NixShellFlag> {
+    Some(match flag {
+        "-p" | "--packages" => NixShellFlag::Packages,
+
+        "--command" | "--run" | "--help" | "--version" => NixShellFlag::Command,
+
+        "--arg" | "--argstr"
+            // `nix-store`
+            | "--option"
+            // From `nix-build` source...
+            | "--override-flake"
+            => NixShellFlag::Arguments(2),
+
+        "--attr" | "-A" | "--exclude" | "--keep"
+            | "-i" // Interpreter, shebang only
+            // `nix-store`
+            | "--add-root"
+            // From `nix-build` source...
+            | "-j" | "--max-jobs"
+            | "--cores"
+            | "--max-silent-time"
+            | "--timeout"
+            | "--store-uri"
+            | "-I" | "--include"
+            | "--eval-store"
+            | "-o" | "--out-link"
+            => NixShellFlag::Arguments(1),
+
+        "--pure" | "--impure"
+            // Changes meaning of positional arguments.
+            | "-E" | "--expr"
+            // `nix-store`
+            | "--dry-run" | "--ignore-unknown" | "--check"
+            // From `nix-build` source...
+            | "-Q" | "--no-build-output"
+            | "-K" | "--keep-failed"
+            | "-k" | "--keep-going"
+            | "--fallback"
+            | "--readonly-mode"
+            | "--no-gc-warning"
+            | "--add-drv-link" | "--indirect"
+            | "--no-out-link" | "--no-link"
+            | "--drv-link"
+            | "--repair"
+            | "--run-env"
+            => NixShellFlag::Arguments(0),
+
+        _ => return None,
+    })
+}
+
+/// How `cached-nix-shell` treats a flag.
+///
+/// `cached-nix-shell` only accepts some of `nix-shell`'s flags, and adds its own.
+///
+/// See: <https://github.com/xzfc/cached-nix-shell/blob/master/src/args.rs>
+fn cached_nix_shell_flag(flag: &str) -> Option<NixShellFlag> {
+    Some(match flag {
+        "-p" | "--packages" => NixShellFlag::Packages,
+
+        "--command" | "--run" | "--help" | "--version"
+            // `cached-nix-shell` only; these run a command or print the environment.
+            | "--exec" | "--wrap" | "--dump-env"
+            => NixShellFlag::Command,
+
+        "--arg" | "--argstr" | "--option" => NixShellFlag::Arguments(2),
+
+        "--attr" | "-A"
+            | "--keep"
+            | "-i"
+            | "-I" | "--include"
+            | "-j" | "--max-jobs"
+            | "--cores"
+            => NixShellFlag::Arguments(1),
+
+        "--pure" | "--impure"
+            | "-E" | "--expr"
+            | "-Q" | "--no-build-output"
+            | "-K" | "--keep-failed"
+            | "-k" | "--keep-going"
+            | "--fallback"
+            | "--show-trace"
+            | "-v" | "--verbose"
+            => NixShellFlag::Arguments(0),
+
+        _ => return None,
+    })
 }
 
 /// Transform arguments to a `nix-shell` invocation to run the specified `command`.
-pub fn transform_nix_shell(args: Vec<String>, command: &str) -> Vec<String> {
+///
+/// Arguments are returned unchanged if `nix-shell` is running a script in shebang mode.
+///
+/// The `backend` determines which flags are recognized.
+pub fn transform_nix_shell(
+    args: Vec<String>,
+    command: &str,
+    backend: NixShellBackend,
+) -> Result<NixShellArgs, MissingFlagArguments> {
+    if is_shebang_invocation(&args) {
+        tracing::debug!(
+            script = args[0],
+            "nix-shell is running a script; not adding `--command`"
+        );
+        return Ok(NixShellArgs {
+            args,
+            command_index: None,
+            packages: Vec::new(),
+            paths: Vec::new(),
+            shebang: true,
+        });
+    }
+
     let mut ret = Vec::with_capacity(args.len() + 2);
     ret.push("--command".into());
     ret.push(command.into());
 
+    let mut positional = Vec::new();
+    let mut packages = false;
+
     let mut i = 0;
     while i < args.len() {
         ret.push(args[i].clone());
         match args[i].as_str() {
-            // Two arguments
-            "--arg" | "--argstr"
-                // `nix-store`
-                | "--option"
-                // From `nix-build` source...
-                | "--override-flake"
-                => {
-                ret.push(args[i + 1].clone());
-                ret.push(args[i + 2].clone());
-                i += 2;
+            "--" => {
+                // End of options; everything after this is a positional argument, even if it
+                // looks like `--command`.
+                ret.extend_from_slice(&args[i + 1..]);
+                positional.extend_from_slice(&args[i + 1..]);
+                break;
             }
 
-            // One argument
-            "--attr" | "-A" | "--exclude" | "--keep"
-                | "-i" // Interpreter, shebang only
-                // `nix-store`
-                | "--add-root"
-                // From `nix-build` source...
-                | "--cores"
-                | "--max-silent-time"
-                | "--timeout"
-                | "--store-uri"
-                | "-I" | "--include"
-                | "--eval-store"
-                | "-o" | "--out-link"
-                => {
-                ret.push(args[i + 1].clone());
-                i += 1;
-            }
+            flag if flag.starts_with('-') => match backend.flag(flag) {
+                // `--packages` changes the meaning of positional arguments.
+                Some(NixShellFlag::Packages) => {
+                    packages = true;
+                }
 
-            // Zero arguments
-            "--pure" | "--impure"
-                // `--packages` changes the meaning of positional arguments, so we effectively
-                // ignore it.
-                | "-p" | "--packages"
-                // Also changes meaning of positional arguments.
-                | "-E" | "--expr"
-                // `nix-store`
-                | "--dry-run" | "--ignore-unknown" | "--check"
-                // From `nix-build` source...
-                | "-Q" | "--no-build-output"
-                | "-K" | "--keep-failed"
-                | "-k" | "--keep-going"
-                | "--fallback"
-                | "--readonly-mode"
-                | "--no-gc-warning"
-                | "--add-drv-link" | "--indirect"
-                | "--no-out-link" | "--no-link"
-                | "--drv-link"
-                | "--repair"
-                | "--run-env"
-                => {
-                // Nothing to skip.
-            }
+                Some(NixShellFlag::Arguments(arity)) => {
+                    ret.extend_from_slice(flag_arguments(backend.program(), &args, i, arity)?);
+                    i += arity;
+                }
 
-            "--command" | "--run"
-                | "--help"
-                | "--version"
-                => {
-                // We already have a command to run; don't add our own `--command {command}`
-                // arguments.
-                return args;
-            }
+                Some(NixShellFlag::Command) => {
+                    // We already have a command to run; don't add our own `--command {command}`
+                    // arguments.
+                    return Ok(NixShellArgs {
+                        args,
+                        command_index: None,
+                        packages: Vec::new(),
+                        paths: Vec::new(),
+                        shebang: false,
+                    });
+                }
+
+                None => {
+                    // Unknown flag, ignore.
+                }
+            },
 
-            _ => {
-                // Unknown argument, ignore.
+            arg => {
+                positional.push(arg.to_owned());
             }
         }
 
         i += 1;
     }
 
-    ret
+    let (packages, paths) = if packages {
+        (positional, Vec::new())
+    } else {
+        (Vec::new(), positional)
+    };
+
+    Ok(NixShellArgs {
+        args: ret,
+        command_index: Some(0),
+        packages,
+        paths,
+        shebang: false,
+    })
+}
+
+/// The most bytes of a script we read to check if `nix-shell` is running it.
+const SHEBANG_READ_LIMIT: u64 = 64 * 1024;
+
+/// Is `nix-shell` being used as a script interpreter, like `nix-shell ./script.py`, where
+/// `script.py` starts with:
+///
+/// ```text
+/// #! /usr/bin/env nix-shell
+/// #! nix-shell -i python3 -p python3
+/// ```
+///
+/// Like `nix-shell` itself, this checks if the first argument is a regular file starting with
+/// `#!`. `nix-shell` doesn't look at its environment to detect shebang mode, so neither do we.
+/// We also require that an interpreter is given with `-i`, either in the arguments or in the
+/// `#! nix-shell` lines directly after the first line, because in that case `-i` conflicts
+/// with `--command`.
+///
+/// See: <https://github.com/NixOS/nix/blob/master/src/nix-build/nix-build.cc>
+fn is_shebang_invocation(args: &[String]) -> bool {
+    let Some(script) = args.first() else {
+        return false;
+    };
+    if !std::fs::metadata(script).is_ok_and(|metadata| metadata.is_file()) {
+        return false;
+    }
+    let Ok(file) = std::fs::File::open(script) else {
+        return false;
+    };
+    let mut lines = BufReader::new(file.take(SHEBANG_READ_LIMIT))
+        .split(b'\n')
+        .map_while(Result::ok)
+        .map(|line| String::from_utf8_lossy(&line).into_owned());
+    if !lines.next().is_some_and(|line| line.starts_with("#!")) {
+        return false;
+    }
+
+    let has_interpreter = |words: &[String]| words.iter().any(|word| word == "-i");
+
+    has_interpreter(&args[1..])
+        || lines
+            .map_while(|line| line.strip_prefix("#!").map(ToOwned::to_owned))
+            .filter_map(|line| {
+                let line = line.trim_start().strip_prefix("nix-shell")?;
+                line.starts_with(char::is_whitespace)
+                    .then(|| shell_words::split(line).ok())
+                    .flatten()
+            })
+            .any(|words| has_interpreter(&words))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::TempDir;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| (*arg).to_owned()).collect()
+    }
+
+    fn transform(given: &[&str]) -> NixArgs {
+        transform_nix(args(given), "fish", &NixCli::snapshot().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_transform_nix() {
+        let nix_args = transform(&["develop", "--impure", ".#foo"]);
+        assert_eq!(
+            nix_args.args,
+            args(&["develop", "--impure", ".#foo", "--command", "fish"])
+        );
+        assert_eq!(nix_args.subcommand_path, args(&["develop"]));
+        assert_eq!(nix_args.subcommand_index, Some(0));
+        assert_eq!(nix_args.command_index, Some(3));
+        assert_eq!(nix_args.installables, args(&[".#foo"]));
+    }
+
+    #[test]
+    fn test_transform_nix_double_dash_first() {
+        let nix_args = transform(&["--", "develop"]);
+        assert_eq!(nix_args.args, args(&["--", "develop"]));
+        assert!(nix_args.subcommand_path.is_empty());
+        assert_eq!(nix_args.command_index, None);
+        assert!(nix_args.installables.is_empty());
+    }
+
+    #[test]
+    fn test_transform_nix_double_dash_after_flags() {
+        let nix_args = transform(&["develop", "--impure", "--", ".#foo"]);
+        assert_eq!(
+            nix_args.args,
+            args(&["develop", "--impure", ".#foo", "--command", "fish"])
+        );
+        assert_eq!(nix_args.command_index, Some(3));
+        assert_eq!(nix_args.installables, args(&[".#foo"]));
+
+        // Arguments after `--` which look like flags have to stay there, so we can't add
+        // `--command`.
+        let nix_args = transform(&["develop", "--impure", "--", "-foo"]);
+        assert_eq!(nix_args.args, args(&["develop", "--impure", "--", "-foo"]));
+        assert_eq!(nix_args.command_index, None);
+        assert_eq!(nix_args.installables, args(&["-foo"]));
+    }
+
+    #[test]
+    fn test_transform_nix_double_dash_flag_argument() {
+        // `--` is the argument to `--profile` here, not the end of options.
+        let nix_args = transform(&["develop", "--profile", "--", ".#foo"]);
+        assert_eq!(
+            nix_args.args,
+            args(&["develop", "--profile", "--", ".#foo", "--command", "fish"])
+        );
+        assert_eq!(nix_args.command_index, Some(4));
+        assert_eq!(nix_args.installables, args(&[".#foo"]));
+    }
+
+    #[test]
+    fn test_transform_nix_command_double_dash() {
+        let nix_args = transform(&["develop", "--command", "--", "ls"]);
+        assert_eq!(nix_args.args, args(&["develop", "--command", "--", "ls"]));
+        assert_eq!(nix_args.command_index, None);
+        assert!(nix_args.installables.is_empty());
+    }
+
+    #[test]
+    fn test_transform_nix_repeated_double_dash() {
+        let nix_args = transform(&["shell", "--", "--", "nixpkgs#hello"]);
+        assert_eq!(nix_args.args, args(&["shell", "--", "--", "nixpkgs#hello"]));
+        assert_eq!(nix_args.command_index, None);
+        assert_eq!(nix_args.installables, args(&["--", "nixpkgs#hello"]));
+    }
+
+    fn missing(given: &[&str]) -> MissingFlagArguments {
+        transform_nix(args(given), "fish", &NixCli::snapshot().unwrap()).unwrap_err()
+    }
+
+    /// The part of the command line the error points at.
+    fn label(err: &MissingFlagArguments) -> &str {
+        &err.command_line[err.span.offset()..err.span.offset() + err.span.len()]
+    }
+
+    #[test]
+    fn test_missing_flag_arguments_long() {
+        let err = missing(&["develop", "--arg", "foo"]);
+        assert_eq!(
+            err.to_string(),
+            "`--arg` expects 2 arguments, but only 1 was given"
+        );
+        assert_eq!((err.expected, err.found), (2, 1));
+        assert_eq!(err.command_line, "nix develop --arg foo");
+        assert_eq!(label(&err), "--arg foo");
+    }
+
+    #[test]
+    fn test_missing_flag_arguments_short() {
+        let err = missing(&["develop", "-s", "NAME"]);
+        assert_eq!(
+            err.to_string(),
+            "`-s` expects 2 arguments, but only 1 was given"
+        );
+        assert_eq!((err.expected, err.found), (2, 1));
+        assert_eq!(label(&err), "-s NAME");
+    }
+
+    #[test]
+    fn test_missing_flag_arguments_at_end() {
+        let err = missing(&["build", ".#foo", "-o"]);
+        assert_eq!(
+            err.to_string(),
+            "`-o` expects 1 argument, but none were given"
+        );
+        assert_eq!((err.expected, err.found), (1, 0));
+        assert_eq!(err.command_line, "nix build '.#foo' -o");
+        assert_eq!(label(&err), "-o");
+    }
+
+    #[test]
+    fn test_set_log_format() {
+        let mut nix_args = transform(&["build", "-vv", ".#foo"]);
+        nix_args.set_log_format("internal-json");
+        assert_eq!(
+            nix_args.args,
+            args(&["build", "--log-format", "internal-json", "-vv", ".#foo"])
+        );
+
+        let mut nix_args = transform(&["build", "--log-format", "bar", "--quiet", ".#foo"]);
+        nix_args.set_log_format("internal-json");
+        assert_eq!(
+            nix_args.args,
+            args(&["build", "--log-format", "internal-json", "--quiet", ".#foo"])
+        );
+    }
+
+    #[test]
+    fn test_print_dev_env_args() {
+        let nix_args = transform(&["develop", "--impure", ".#foo"]);
+        assert_eq!(
+            nix_args.print_dev_env_args().unwrap(),
+            args(&["print-dev-env", "--json", "--impure", ".#foo"])
+        );
+
+        assert_eq!(transform(&["shell", ".#foo"]).print_dev_env_args(), None);
+        assert_eq!(
+            transform(&["develop", "--command", "ls"]).print_dev_env_args(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_print_dev_env_args_develop_only_flags() {
+        for given in [
+            &["develop", "-i", ".#foo"][..],
+            &["develop", "-k", "HOME"],
+            &["develop", "--unset-env-var", "HOME"],
+            &["develop", "-s", "NAME", "value"],
+            &["develop", "--phase", "build"],
+            &["develop", "--unpack"],
+            &["develop", "--profile", "./profile"],
+        ] {
+            assert_eq!(transform(given).print_dev_env_args(), None, "{given:?}");
+        }
+    }
+
+    #[test]
+    fn test_is_shebang_invocation() {
+        let dir = TempDir::new();
+        let script = |name, contents| dir.write(name, contents).into_string();
+
+        let path = script(
+            "shebang",
+            "#! /usr/bin/env nix-shell\n#! nix-shell -i python3 -p python3\nprint('hi')\n",
+        );
+        assert!(is_shebang_invocation(&args(&[&path, "arg"])));
+
+        // The interpreter can also be given on the command line.
+        let path = script("shebang-args", "#! /usr/bin/env nix-shell\necho hi\n");
+        assert!(!is_shebang_invocation(&args(&[&path])));
+        assert!(is_shebang_invocation(&args(&[&path, "-i", "bash"])));
+
+        // Only the `#!` lines directly after the first line count.
+        let path = script(
+            "shebang-late",
+            "#! /usr/bin/env nix-shell\necho hi\n#! nix-shell -i bash\n",
+        );
+        assert!(!is_shebang_invocation(&args(&[&path])));
+
+        let path = script(
+            "not-shebang",
+            "{ pkgs ? import <nixpkgs> {} }: pkgs.mkShell {}\n",
+        );
+        assert!(!is_shebang_invocation(&args(&[&path, "-i", "bash"])));
+
+        // Directories aren't scripts.
+        assert!(!is_shebang_invocation(&args(&[
+            dir.path().as_str(),
+            "-i",
+            "bash"
+        ])));
+        assert!(!is_shebang_invocation(&args(&["--pure", "-i", "bash"])));
+        assert!(!is_shebang_invocation(&[]));
+    }
+
+    #[test]
+    fn test_transform_nix_shell_backends() {
+        let transform =
+            |given: &[&str], backend| transform_nix_shell(args(given), "fish", backend).unwrap();
+
+        let nix_shell_args = transform(&["-j", "4", "shell.nix"], NixShellBackend::NixShell);
+        assert_eq!(nix_shell_args.command_index, Some(0));
+        assert_eq!(nix_shell_args.paths, args(&["shell.nix"]));
+
+        let nix_shell_args = transform(
+            &["-p", "hello", "--max-jobs", "4"],
+            NixShellBackend::CachedNixShell,
+        );
+        assert_eq!(nix_shell_args.command_index, Some(0));
+        assert_eq!(nix_shell_args.packages, args(&["hello"]));
+
+        for flag in ["--exec", "--wrap", "--dump-env", "--run"] {
+            let nix_shell_args = transform(&[flag, "true"], NixShellBackend::CachedNixShell);
+            assert_eq!(nix_shell_args.args, args(&[flag, "true"]));
+            assert_eq!(nix_shell_args.command_index, None);
+        }
+
+        // `nix-shell` doesn't know `cached-nix-shell`'s flags.
+        let nix_shell_args = transform(&["--wrap"], NixShellBackend::NixShell);
+        assert_eq!(nix_shell_args.command_index, Some(0));
+    }
+
+    #[test]
+    fn test_transform_nix_shell_double_dash() {
+        let nix_shell_args = transform_nix_shell(
+            args(&["--pure", "--", "--command", "shell.nix"]),
+            "fish",
+            NixShellBackend::NixShell,
+        )
+        .unwrap();
+        assert_eq!(
+            nix_shell_args.args,
+            args(&[
+                "--command",
+                "fish",
+                "--pure",
+                "--",
+                "--command",
+                "shell.nix"
+            ])
+        );
+        assert_eq!(nix_shell_args.command_index, Some(0));
+        assert_eq!(nix_shell_args.paths, args(&["--command", "shell.nix"]));
+        assert!(nix_shell_args.packages.is_empty());
+    }
 }
@@ -0,0 +1,226 @@
+use std::collections::BTreeMap;
+use std::process;
+
+use camino::Utf8Path;
+use miette::miette;
+use miette::Context;
+use miette::IntoDiagnostic;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::dirs;
+use crate::fs;
+
+/// A snapshot of `nix __dump-cli`, trimmed to the fields we use.
+///
+/// Regenerate with:
+///
+/// ```sh
+/// nix __dump-cli | jq '
+///   def trim: {flags: (.flags // {} | map_values(
+///       {arity, labels, shortName, aliasFor} | with_entries(select(.value != null))
+///     ))}
+///     + if .commands then {commands: (.commands | map_values(trim))} else {} end;
+///   {args: (.args | trim)}
+/// ' > data/nix-cli.json
+/// ```
+const SNAPSHOT: &str = include_str!("../data/nix-cli.json");
+
+/// The name of the file in the cache directory holding the flags of the installed `nix`.
+const CACHE_FILE: &str = "nix-cli.json";
+
+/// The flags and subcommands of the `nix` CLI, as printed by `nix __dump-cli`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NixCli {
+    args: Args,
+}
+
+/// The flags and subcommands of a `nix` command.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Args {
+    #[serde(default)]
+    flags: BTreeMap<String, Flag>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    commands: BTreeMap<String, Args>,
+}
+
+/// A flag to a `nix` command, like `--file` or `-L`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Flag {
+    /// The number of arguments the flag takes. Defaults to the number of `labels`.
+    ///
+    /// This is missing for flags which take the rest of the arguments, like `--command`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    arity: Option<usize>,
+    /// Names for the flag's arguments.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    labels: Vec<String>,
+    /// The flag's one-character short name, like `L` for `--print-build-logs`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    short_name: Option<String>,
+    /// If this flag is an alias, the name of the flag it's an alias for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    alias_for: Option<String>,
+}
+
+/// A cached copy of the installed `nix`'s flags.
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheEntry {
+    /// The canonicalized path of the `nix` executable the flags were read from.
+    nix: String,
+    cli: NixCli,
+}
+
+impl NixCli {
+    /// Load the built-in snapshot of `nix`'s flags.
+    pub fn snapshot() -> miette::Result<Self> {
+        serde_json::from_str(SNAPSHOT)
+            .into_diagnostic()
+            .wrap_err("Failed to parse built-in `nix __dump-cli` snapshot")
+    }
+
+    /// Load the flags of the `nix` executable at the given path with `nix __dump-cli`.
+    ///
+    /// The flags are cached on disk until the path to `nix` (after resolving symlinks) changes.
+    pub fn installed(nix: &Utf8Path) -> miette::Result<Self> {
+        let nix = nix
+            .canonicalize_utf8()
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to resolve path to {nix}"))?;
+        let cache_path = dirs::cache_dir()?.join(CACHE_FILE);
+
+        if let Ok(contents) = std::fs::read_to_string(&cache_path) {
+            match serde_json::from_str::<CacheEntry>(&contents) {
+                Ok(entry) if entry.nix == nix.as_str() => {
+                    tracing::debug!(path = %cache_path, "Using cached `nix` flags");
+                    return Ok(entry.cli);
+                }
+                Ok(_) => {
+                    tracing::debug!(path = %cache_path, "Cached `nix` flags are out of date");
+                }
+                Err(err) => {
+                    tracing::debug!(path = %cache_path, %err, "Failed to parse cached `nix` flags");
+                }
+            }
+        }
+
+        tracing::debug!(%nix, "Reading flags with `nix __dump-cli`");
+        let output = process::Command::new(&nix)
+            .arg("__dump-cli")
+            .stderr(process::Stdio::inherit())
+            .output()
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Unable to launch {nix} __dump-cli"))?;
+        if !output.status.success() {
+            return Err(miette!("{nix} __dump-cli failed: {}", output.status));
+        }
+        let cli: Self = serde_json::from_slice(&output.stdout)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to parse `{nix} __dump-cli` output"))?;
+
+        let entry = CacheEntry {
+            nix: nix.into_string(),
+            cli,
+        };
+        if let Err(err) = write_cache(&cache_path, &entry) {
+            tracing::warn!(path = %cache_path, "Failed to cache `nix` flags: {err:?}");
+        }
+        Ok(entry.cli)
+    }
+
+    /// Is `name` a subcommand of the command at `path`?
+    ///
+    /// For example, `install` is a subcommand of `["profile"]`, and `develop` is a subcommand of
+    /// `[]` (the top-level `nix` command).
+    pub fn is_subcommand(&self, path: &[String], name: &str) -> bool {
+        self.command(path)
+            .map(|args| args.commands.contains_key(name))
+            .unwrap_or(false)
+    }
+
+    /// The number of arguments taken by `flag` (like `--file` or `-L`) in the command at `path`.
+    ///
+    /// The flags of the command at `path` are checked first, then the flags of its parent
+    /// commands, ending with the global flags.
+    ///
+    /// Returns `None` if the flag is unknown.
+    pub fn arity(&self, path: &[String], flag: &str) -> Option<usize> {
+        (0..=path.len())
+            .rev()
+            .filter_map(|depth| self.command(&path[..depth]))
+            .find_map(|args| args.arity(flag))
+    }
+
+    /// Get the command at `path`, like `["profile", "install"]`.
+    fn command(&self, path: &[String]) -> Option<&Args> {
+        path.iter()
+            .try_fold(&self.args, |args, name| args.commands.get(name))
+    }
+}
+
+impl Args {
+    /// The number of arguments taken by `flag` in this command.
+    fn arity(&self, flag: &str) -> Option<usize> {
+        let found = if let Some(long) = flag.strip_prefix("--") {
+            self.flags.get(long)?
+        } else if let Some(short) = flag.strip_prefix('-') {
+            self.flags
+                .values()
+                .find(|found| found.short_name.as_deref() == Some(short))?
+        } else {
+            return None;
+        };
+
+        let found = match &found.alias_for {
+            Some(name) => self.flags.get(name)?,
+            None => found,
+        };
+
+        Some(found.arity.unwrap_or(found.labels.len()))
+    }
+}
+
+fn write_cache(path: &Utf8Path, entry: &CacheEntry) -> miette::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to create {parent}"))?;
+    }
+    let contents = serde_json::to_string(entry).into_diagnostic()?;
+    fs::write_atomic(path, &contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(path: &[&str]) -> Vec<String> {
+        path.iter().map(|name| (*name).to_owned()).collect()
+    }
+
+    #[test]
+    fn test_arity_per_subcommand() {
+        let cli = NixCli::snapshot().unwrap();
+        // `-s` is `--substitute-on-destination` in `nix copy`, but `--set-env-var` in
+        // `nix develop`.
+        assert_eq!(cli.arity(&path(&["copy"]), "-s"), Some(0));
+        assert_eq!(cli.arity(&path(&["develop"]), "-s"), Some(2));
+        assert_eq!(cli.arity(&path(&["build"]), "-s"), None);
+        // Global flags are found from any subcommand.
+        assert_eq!(cli.arity(&[], "--substituters"), Some(1));
+        assert_eq!(cli.arity(&path(&["develop"]), "--substituters"), Some(1));
+    }
+
+    #[test]
+    fn test_arity_nested() {
+        let cli = NixCli::snapshot().unwrap();
+        let profile_install = path(&["profile", "install"]);
+        assert!(cli.is_subcommand(&path(&["profile"]), "install"));
+        assert_eq!(cli.arity(&profile_install, "--priority"), Some(1));
+        assert_eq!(cli.arity(&path(&["profile"]), "--priority"), None);
+        // Flags of parent commands and global flags are found too.
+        assert_eq!(cli.arity(&profile_install, "--profile"), Some(1));
+        assert_eq!(cli.arity(&profile_install, "--substituters"), Some(1));
+    }
+}
@@ -0,0 +1,278 @@
+use serde::Deserialize;
+
+use crate::dirs;
+
+/// The name of the file in the cache directory which records the session we last warned about
+/// `nom` being missing in.
+const MISSING_MARKER: &str = "nom-missing";
+
+/// Subcommands which `nom` supports directly, like `nom build`.
+const NATIVE_SUBCOMMANDS: &[&str] = &["build", "shell", "develop", "nix-shell", "nix-build"];
+
+/// Subcommands which are run with `nom --json` by default.
+///
+/// `nix run` isn't included because the program it runs shares its stderr with `nix`.
+const JSON_SUBCOMMANDS: &[&str] = &["profile install", "flake check", "copy"];
+
+/// When to use `nom` (`nix-output-monitor`) instead of `nix`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum NomMode {
+    /// Use `nom` if it's installed, and fall back to `nix` otherwise.
+    Auto,
+    /// Always use `nom`.
+    Always,
+    /// Never use `nom`.
+    Never,
+}
+
+impl NomMode {
+    /// Should `program` (`nom` or `nom-shell`) be used?
+    ///
+    /// In `auto` mode, this checks that `program` is on the `$PATH`, and warns once per session
+    /// (usually a terminal) if it isn't.
+    pub fn resolve(self, program: &str) -> bool {
+        match self {
+            NomMode::Never => false,
+            NomMode::Always => true,
+            NomMode::Auto => {
+                let marker = dirs::cache_dir().map(|dir| dir.join(MISSING_MARKER));
+                if crate::find_executable(program).is_some() {
+                    if let Ok(marker) = marker {
+                        // Warn again if `nom` goes missing later.
+                        let _ = std::fs::remove_file(marker);
+                    }
+                    return true;
+                }
+
+                let session = session_id();
+                match marker {
+                    Ok(marker)
+                        if std::fs::read_to_string(&marker)
+                            .is_ok_and(|warned| warned == session) =>
+                    {
+                        tracing::debug!("`{program}` isn't on the `$PATH`; not using `nom`");
+                    }
+                    marker => {
+                        tracing::warn!(
+                            "`{program}` isn't on the `$PATH`; not using `nom`\n\
+                            Note: This warning is only shown once per session; use \
+                            `--nom=always` to require `nom` or `--nom=never` to disable it"
+                        );
+                        if let Ok(marker) = marker {
+                            if let Some(parent) = marker.parent() {
+                                let _ = std::fs::create_dir_all(parent);
+                            }
+                            let _ = std::fs::write(marker, session);
+                        }
+                    }
+                }
+                false
+            }
+        }
+    }
+
+    /// This mode as a command-line argument, like `--nom=auto`.
+    pub fn as_arg(self) -> &'static str {
+        match self {
+            NomMode::Auto => "--nom=auto",
+            NomMode::Always => "--nom=always",
+            NomMode::Never => "--nom=never",
+        }
+    }
+}
+
+/// An identifier for the current session, which is shared by the processes started from the
+/// same terminal.
+fn session_id() -> String {
+    // SAFETY: `getsid(0)` only reads the session ID of the current process.
+    let sid = unsafe { libc::getsid(0) };
+    sid.to_string()
+}
+
+/// How to run a subcommand with `nom`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NomPolicy {
+    /// Run the subcommand with `nom` directly, like `nom build`.
+    Native,
+    /// Run the subcommand with `nix --log-format internal-json` and pipe its logs into
+    /// `nom --json`.
+    Json,
+    /// Don't use `nom`.
+    Never,
+}
+
+impl NomPolicy {
+    /// The default policy for a subcommand, like `build` or `profile install`.
+    pub fn default_for(subcommand: &str) -> Self {
+        if NATIVE_SUBCOMMANDS.contains(&subcommand) {
+            NomPolicy::Native
+        } else if JSON_SUBCOMMANDS.contains(&subcommand) {
+            NomPolicy::Json
+        } else {
+            NomPolicy::Never
+        }
+    }
+}
+
+/// The `nom` setting for a subcommand in the configuration file.
+///
+/// This is a policy (`"native"`, `"json"`, or `"never"`) or a boolean, where `true` means
+/// `"native"` for subcommands `nom` supports directly and `"json"` otherwise.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(untagged)]
+pub enum NomSetting {
+    Enabled(bool),
+    Policy(NomPolicy),
+}
+
+impl NomSetting {
+    /// The policy for the given subcommand.
+    pub fn policy(self, subcommand: &str) -> NomPolicy {
+        match self {
+            NomSetting::Enabled(false) => NomPolicy::Never,
+            NomSetting::Enabled(true) if NATIVE_SUBCOMMANDS.contains(&subcommand) => {
+                NomPolicy::Native
+            }
+            NomSetting::Enabled(true) => NomPolicy::Json,
+            NomSetting::Policy(policy) => policy,
+        }
+    }
+}
+
+/// Decide how to run a subcommand with `nom`, given the global mode (if it's set) and the
+/// subcommand's setting from the configuration file (if any).
+///
+/// Enabling `nom` for a subcommand uses `auto` mode unless a mode is set globally; an explicit
+/// `--nom=never` always wins.
+///
+/// In `auto` mode, this checks that `nom` is installed; see [`NomMode::resolve`].
+pub fn nom_policy(
+    mode: Option<NomMode>,
+    subcommand: &str,
+    setting: Option<NomSetting>,
+) -> NomPolicy {
+    let Some((mode, policy)) = plan(mode, subcommand, setting) else {
+        return NomPolicy::Never;
+    };
+    let program = program(subcommand);
+    if mode.resolve(program) {
+        policy
+    } else {
+        NomPolicy::Never
+    }
+}
+
+/// The `nom` program which runs `subcommand`, like `nom-shell` for `nix-shell`.
+pub fn program(subcommand: &str) -> &'static str {
+    match subcommand {
+        "nix-shell" => "nom-shell",
+        "nix-build" => "nom-build",
+        _ => "nom",
+    }
+}
+
+/// The mode and policy to use for a subcommand before checking whether `nom` is installed, or
+/// `None` if `nom` shouldn't be used.
+fn plan(
+    mode: Option<NomMode>,
+    subcommand: &str,
+    setting: Option<NomSetting>,
+) -> Option<(NomMode, NomPolicy)> {
+    let (mode, policy) = match (mode, setting) {
+        (Some(NomMode::Never), _) | (None, None) => return None,
+        (None, Some(setting)) => (NomMode::Auto, setting.policy(subcommand)),
+        (Some(mode), Some(setting)) => (mode, setting.policy(subcommand)),
+        (Some(mode), None) => (mode, NomPolicy::default_for(subcommand)),
+    };
+    (policy != NomPolicy::Never).then_some((mode, policy))
+}
+
+impl<'de> Deserialize<'de> for NomMode {
+    /// Deserialize from `"auto"`, `"always"`, or `"never"`, or from a boolean, where `true` is
+    /// `auto`.
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Bool(bool),
+            Mode(String),
+        }
+
+        match Repr::deserialize(deserializer)? {
+            Repr::Bool(true) => Ok(NomMode::Auto),
+            Repr::Bool(false) => Ok(NomMode::Never),
+            Repr::Mode(mode) => match mode.as_str() {
+                "auto" => Ok(NomMode::Auto),
+                "always" => Ok(NomMode::Always),
+                "never" => Ok(NomMode::Never),
+                _ => Err(serde::de::Error::unknown_variant(
+                    &mode,
+                    &["auto", "always", "never"],
+                )),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan_unset() {
+        assert_eq!(plan(None, "build", None), None);
+        assert_eq!(
+            plan(None, "build", Some(NomSetting::Enabled(true))),
+            Some((NomMode::Auto, NomPolicy::Native))
+        );
+        assert_eq!(
+            plan(None, "eval", Some(NomSetting::Policy(NomPolicy::Json))),
+            Some((NomMode::Auto, NomPolicy::Json))
+        );
+    }
+
+    #[test]
+    fn test_plan_never() {
+        for setting in [
+            None,
+            Some(NomSetting::Enabled(true)),
+            Some(NomSetting::Policy(NomPolicy::Native)),
+        ] {
+            assert_eq!(plan(Some(NomMode::Never), "build", setting), None);
+        }
+    }
+
+    #[test]
+    fn test_plan_enabled() {
+        assert_eq!(
+            plan(Some(NomMode::Always), "build", None),
+            Some((NomMode::Always, NomPolicy::Native))
+        );
+        assert_eq!(
+            plan(Some(NomMode::Auto), "copy", None),
+            Some((NomMode::Auto, NomPolicy::Json))
+        );
+        assert_eq!(plan(Some(NomMode::Auto), "run", None), None);
+        assert_eq!(
+            plan(Some(NomMode::Auto), "nix-build", None),
+            Some((NomMode::Auto, NomPolicy::Native))
+        );
+        assert_eq!(
+            plan(
+                Some(NomMode::Auto),
+                "build",
+                Some(NomSetting::Enabled(false))
+            ),
+            None
+        );
+        assert_eq!(
+            plan(
+                Some(NomMode::Always),
+                "run",
+                Some(NomSetting::Enabled(true))
+            ),
+            Some((NomMode::Always, NomPolicy::Json))
+        );
+    }
+}
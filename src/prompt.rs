@@ -0,0 +1,231 @@
+use miette::miette;
+use miette::IntoDiagnostic;
+use serde::Serialize;
+
+use crate::shell::ShellKind;
+
+/// Environment variable holding the number of Nix shells the current shell is nested in.
+pub const DEPTH_VAR: &str = "NIX_YOUR_SHELL_DEPTH";
+
+/// Environment variable holding the command which launched the current shell, like `develop`,
+/// `shell`, or `nix-shell`.
+pub const SUBCOMMAND_VAR: &str = "NIX_YOUR_SHELL_SUBCOMMAND";
+
+/// Environment variable holding the space-separated packages (from `nix-shell --packages`) in
+/// the current shell and the shells it's nested in.
+pub const PACKAGES_VAR: &str = "NIX_YOUR_SHELL_PACKAGES";
+
+/// Environment variable holding the space-separated installables (like `nixpkgs#hello` or
+/// `.#default`) in the current shell and the shells it's nested in.
+pub const INSTALLABLES_VAR: &str = "NIX_YOUR_SHELL_INSTALLABLES";
+
+/// Information about the Nix shells the current shell is nested in, for display in prompts.
+#[derive(Debug, Default, Serialize)]
+pub struct ShellInfo {
+    depth: usize,
+    subcommand: Option<String>,
+    packages: Vec<String>,
+    installables: Vec<String>,
+}
+
+impl ShellInfo {
+    /// Read the information exported by the shell we're running in, if any.
+    pub fn from_env() -> Self {
+        let words = |var| {
+            std::env::var(var)
+                .unwrap_or_default()
+                .split_whitespace()
+                .map(ToOwned::to_owned)
+                .collect()
+        };
+        Self {
+            depth: std::env::var(DEPTH_VAR)
+                .ok()
+                .and_then(|depth| depth.parse().ok())
+                .unwrap_or(0),
+            subcommand: std::env::var(SUBCOMMAND_VAR)
+                .ok()
+                .filter(|subcommand| !subcommand.is_empty()),
+            packages: words(PACKAGES_VAR),
+            installables: words(INSTALLABLES_VAR),
+        }
+    }
+
+    /// The information for a new shell nested in this one, launched by `subcommand` with the
+    /// given packages and installables.
+    pub fn enter(mut self, subcommand: &str, packages: &[String], installables: &[String]) -> Self {
+        self.depth += 1;
+        self.subcommand = Some(subcommand.to_owned());
+        self.packages.extend_from_slice(packages);
+        self.installables.extend_from_slice(installables);
+        self
+    }
+
+    /// The environment variables to export to the shell.
+    pub fn env_vars(&self) -> [(&'static str, String); 4] {
+        [
+            (DEPTH_VAR, self.depth.to_string()),
+            (SUBCOMMAND_VAR, self.subcommand.clone().unwrap_or_default()),
+            (PACKAGES_VAR, self.packages.join(" ")),
+            (INSTALLABLES_VAR, self.installables.join(" ")),
+        ]
+    }
+
+    /// Render the information in the given format.
+    ///
+    /// Text is empty outside of a Nix shell, so that prompt modules are hidden. The `executable`
+    /// is the `nix-your-shell` to call from prompt configurations.
+    pub fn render(&self, format: PromptFormat, executable: &str) -> miette::Result<String> {
+        match format {
+            PromptFormat::Json => serde_json::to_string(self).into_diagnostic(),
+            PromptFormat::Text => Ok(self.text()),
+            PromptFormat::Starship => Ok(starship_config(executable)),
+            PromptFormat::OhMyPosh => oh_my_posh_config(executable),
+        }
+    }
+
+    /// A short description of the current Nix shell, like `develop .#default`, or an empty string
+    /// outside of a Nix shell.
+    fn text(&self) -> String {
+        let Some(subcommand) = &self.subcommand else {
+            return String::new();
+        };
+        let mut ret = std::iter::once(subcommand)
+            .chain(&self.packages)
+            .chain(&self.installables)
+            .map(|word| word.as_str())
+            .collect::<Vec<_>>()
+            .join(" ");
+        if self.depth > 1 {
+            ret.push_str(&format!(" ({})", self.depth));
+        }
+        ret
+    }
+}
+
+/// A format to print the current Nix shell's information in.
+#[derive(Debug, Clone, Copy, Default, clap::ValueEnum)]
+pub enum PromptFormat {
+    /// A JSON object with `depth`, `subcommand`, `packages`, and `installables` fields.
+    #[default]
+    Json,
+    /// A short description for prompt modules, like `develop .#default`, and nothing outside of
+    /// Nix shells.
+    Text,
+    /// A `starship` custom module which shows the `text` format.
+    Starship,
+    /// An `oh-my-posh` command segment which shows the `text` format.
+    OhMyPosh,
+}
+
+/// The command prompt configurations run to get the `text` format, for `sh`.
+fn text_command(executable: &str) -> String {
+    format!("{} prompt --format text", shell_words::quote(executable))
+}
+
+/// A `starship` custom module showing the current Nix shell.
+///
+/// See: <https://starship.rs/config/#custom-commands>
+fn starship_config(executable: &str) -> String {
+    let string = |value: &str| toml::Value::from(value).to_string();
+    format!(
+        "[custom.nix_your_shell]\n\
+        command = {}\n\
+        when = {}\n\
+        shell = [\"sh\"]\n\
+        symbol = \"❄️ \"\n\
+        style = \"bold blue\"",
+        string(&text_command(executable)),
+        string(&format!("test -n \"${DEPTH_VAR}\"")),
+    )
+}
+
+/// An `oh-my-posh` command segment showing the current Nix shell.
+///
+/// See: <https://ohmyposh.dev/docs/segments/system/command>
+fn oh_my_posh_config(executable: &str) -> miette::Result<String> {
+    serde_json::to_string_pretty(&serde_json::json!({
+        "type": "command",
+        "style": "plain",
+        "foreground": "blue",
+        "properties": {
+            "shell": "sh",
+            "command": text_command(executable),
+        },
+        "template": "❄️ {{ .Output }}",
+    }))
+    .into_diagnostic()
+}
+
+/// Shell code defining a prompt segment which shows the packages and nesting depth of the
+/// current Nix shell.
+pub fn prompt_segment(kind: &ShellKind) -> miette::Result<&'static str> {
+    prompt_segment_source(kind).ok_or_else(|| {
+        miette!(
+            "I don't know how to print a prompt segment for `{kind}`\n\
+            Note: Supported shells are: {}\n\
+            Note: Other shells can read the `${DEPTH_VAR}`, `${PACKAGES_VAR}`, and \
+            `${INSTALLABLES_VAR}` environment variables",
+            ShellKind::list("and", |kind| prompt_segment_source(kind).is_some())
+        )
+    })
+}
+
+fn prompt_segment_source(kind: &ShellKind) -> Option<&'static str> {
+    match kind {
+        ShellKind::Fish => Some(include_str!("../data/prompt.fish")),
+        ShellKind::Zsh => Some(include_str!("../data/prompt.zsh")),
+        ShellKind::Nushell => Some(include_str!("../data/prompt.nu")),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shell_info() -> ShellInfo {
+        ShellInfo::default().enter("develop", &[], &[".#default".to_owned()])
+    }
+
+    #[test]
+    fn test_render_text() {
+        let render = |info: ShellInfo| info.render(PromptFormat::Text, "nix-your-shell").unwrap();
+        assert_eq!(render(ShellInfo::default()), "");
+        assert_eq!(render(shell_info()), "develop .#default");
+        assert_eq!(
+            render(shell_info().enter("shell", &["hello".to_owned()], &[])),
+            "shell hello .#default (2)"
+        );
+    }
+
+    #[test]
+    fn test_render_starship() {
+        let config = shell_info()
+            .render(PromptFormat::Starship, "/home/me/my bin/nix-your-shell")
+            .unwrap();
+        let config: toml::Table = toml::from_str(&config).unwrap();
+        let module = &config["custom"]["nix_your_shell"];
+        assert_eq!(
+            module["command"].as_str().unwrap(),
+            "'/home/me/my bin/nix-your-shell' prompt --format text"
+        );
+        assert_eq!(
+            module["when"].as_str().unwrap(),
+            "test -n \"$NIX_YOUR_SHELL_DEPTH\""
+        );
+    }
+
+    #[test]
+    fn test_render_oh_my_posh() {
+        let config = shell_info()
+            .render(PromptFormat::OhMyPosh, "nix-your-shell")
+            .unwrap();
+        let config: serde_json::Value = serde_json::from_str(&config).unwrap();
+        assert_eq!(config["type"], "command");
+        assert_eq!(
+            config["properties"]["command"],
+            "nix-your-shell prompt --format text"
+        );
+    }
+}
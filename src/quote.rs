@@ -0,0 +1,129 @@
+use std::fmt::Write;
+
+use crate::shell::OilsShell;
+use crate::shell::ShellKind;
+
+/// Quote a string as a single word for the given shell, like an executable's path.
+///
+/// Strings which don't need quoting, like `nix-your-shell` or `--native`, are returned as they
+/// are. Returns `None` if we don't know how to quote the string for the shell.
+pub fn word(kind: &ShellKind, value: &str) -> Option<String> {
+    // In PowerShell, `,` separates the elements of an array.
+    let is_bare = |c| is_bare(c) && !(matches!(kind, ShellKind::PowerShell) && c == ',');
+    if !value.is_empty() && value.chars().all(is_bare) {
+        return Some(value.to_owned());
+    }
+    let quoted = match kind {
+        ShellKind::Zsh
+        | ShellKind::Bash
+        | ShellKind::Sh
+        | ShellKind::Dash
+        | ShellKind::Ksh
+        | ShellKind::Mksh
+        | ShellKind::Oils(OilsShell::Osh) => posix(value),
+        ShellKind::Fish => fish(value),
+        ShellKind::Nushell => nu(value),
+        ShellKind::Xonsh => xonsh(value),
+        ShellKind::Elvish => elvish(value),
+        ShellKind::PowerShell => powershell(value),
+        ShellKind::Tcsh => tcsh(value),
+        ShellKind::Oils(OilsShell::Ysh) => ysh(value),
+        // Single-quoted strings are verbatim in `murex` and `ion`, and can't contain `'`.
+        ShellKind::Murex | ShellKind::Ion if !value.contains('\'') => format!("'{value}'"),
+        ShellKind::Murex | ShellKind::Ion | ShellKind::Other(_) => return None,
+    };
+    Some(quoted)
+}
+
+/// Can `c` appear in an unquoted word in every shell we support?
+fn is_bare(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "_-./:+,".contains(c)
+}
+
+/// Quote a string for POSIX shells.
+pub fn posix(value: &str) -> String {
+    shell_words::quote(value).into_owned()
+}
+
+/// Quote a string for `fish`.
+pub fn fish(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// Quote a string for `xonsh`.
+pub fn xonsh(value: &str) -> String {
+    // JSON strings are valid Python string literals.
+    serde_json::Value::from(value).to_string()
+}
+
+/// Quote a string for `elvish`.
+pub fn elvish(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Quote a string for PowerShell.
+pub fn powershell(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// Quote a string for `tcsh`.
+///
+/// Single quotes can't be escaped inside single-quoted strings, `!` is subject to history
+/// substitution even inside quotes, and newlines end the command unless they're escaped.
+pub fn tcsh(value: &str) -> String {
+    format!(
+        "'{}'",
+        value
+            .replace('\'', r"'\''")
+            .replace('!', r"\!")
+            .replace('\n', "\\\n")
+    )
+}
+
+/// Quote a string for `ysh` as a J8 string.
+pub fn ysh(value: &str) -> String {
+    let mut ret = String::from("u'");
+    for c in value.chars() {
+        match c {
+            '\\' => ret.push_str(r"\\"),
+            '\'' => ret.push_str(r"\'"),
+            c if c.is_control() => {
+                let _ = write!(ret, r"\u{{{:x}}}", c as u32);
+            }
+            c => ret.push(c),
+        }
+    }
+    ret.push('\'');
+    ret
+}
+
+/// Quote a string for `nu` as a raw string.
+pub fn nu(value: &str) -> String {
+    let mut hashes = String::from("#");
+    while value.contains(&format!("'{hashes}")) {
+        hashes.push('#');
+    }
+    format!("r{hashes}'{value}'{hashes}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tcsh() {
+        assert_eq!(tcsh("it's"), r"'it'\''s'");
+        assert_eq!(tcsh("hello!"), r"'hello\!'");
+        assert_eq!(tcsh("one\ntwo\nthree"), "'one\\\ntwo\\\nthree'");
+    }
+
+    #[test]
+    fn test_word() {
+        assert_eq!(word(&ShellKind::Tcsh, "--native").unwrap(), "--native");
+        assert_eq!(word(&ShellKind::Tcsh, "a\nb").unwrap(), "'a\\\nb'");
+        assert_eq!(word(&ShellKind::Bash, "").unwrap(), "''");
+        assert_eq!(word(&ShellKind::Murex, "it's"), None);
+        assert_eq!(word(&ShellKind::PowerShell, "a,b").unwrap(), "'a,b'");
+        assert_eq!(word(&ShellKind::Bash, "a,b").unwrap(), "a,b");
+    }
+}
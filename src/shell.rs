@@ -1,4 +1,5 @@
 use std::fmt::Display;
+use std::str::FromStr;
 
 use camino::Utf8Path;
 use camino::Utf8PathBuf;
@@ -27,10 +28,62 @@ pub enum ShellKind {
     /// <https://xon.sh>
     Xonsh,
 
+    /// The `elvish` shell.
+    /// <https://elv.sh/>
+    Elvish,
+
+    /// PowerShell (`pwsh`).
+    /// <https://learn.microsoft.com/en-us/powershell/>
+    PowerShell,
+
+    /// The `tcsh` shell, also used for `csh`.
+    /// <https://www.tcsh.org/>
+    Tcsh,
+
+    /// A POSIX `sh`.
+    /// <https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html>
+    Sh,
+
+    /// The Debian Almquist shell.
+    /// <http://gondor.apana.org.au/~herbert/dash/>
+    Dash,
+
+    /// The Korn shell.
+    /// <http://kornshell.com/>
+    Ksh,
+
+    /// The MirBSD Korn shell.
+    /// <http://www.mirbsd.org/mksh.htm>
+    Mksh,
+
+    /// A shell from the Oils project.
+    /// <https://www.oilshell.org/>
+    Oils(OilsShell),
+
+    /// The `murex` shell.
+    /// <https://murex.rocks/>
+    Murex,
+
+    /// The `ion` shell.
+    /// <https://gitlab.redox-os.org/redox-os/ion>
+    Ion,
+
     /// A different shell.
     Other(String),
 }
 
+/// A shell from the Oils project.
+///
+/// These are built from the same binary, but `osh` is compatible with POSIX `sh` (and `bash`) and
+/// `ysh` is not, so they need different shell environment code.
+#[derive(Clone, Debug)]
+pub enum OilsShell {
+    /// The POSIX- and `bash`-compatible `osh`.
+    Osh,
+    /// The new `ysh` language.
+    Ysh,
+}
+
 impl Display for ShellKind {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -39,11 +92,116 @@ impl Display for ShellKind {
             ShellKind::Bash => write!(f, "bash"),
             ShellKind::Nushell => write!(f, "nu"),
             ShellKind::Xonsh => write!(f, "xonsh"),
+            ShellKind::Elvish => write!(f, "elvish"),
+            ShellKind::PowerShell => write!(f, "pwsh"),
+            ShellKind::Tcsh => write!(f, "tcsh"),
+            ShellKind::Sh => write!(f, "sh"),
+            ShellKind::Dash => write!(f, "dash"),
+            ShellKind::Ksh => write!(f, "ksh"),
+            ShellKind::Mksh => write!(f, "mksh"),
+            ShellKind::Oils(OilsShell::Osh) => write!(f, "osh"),
+            ShellKind::Oils(OilsShell::Ysh) => write!(f, "ysh"),
+            ShellKind::Murex => write!(f, "murex"),
+            ShellKind::Ion => write!(f, "ion"),
             ShellKind::Other(shell) => write!(f, "{shell}"),
         }
     }
 }
 
+impl ShellKind {
+    /// Every kind of shell we know about, besides [`ShellKind::Other`].
+    pub fn known() -> [ShellKind; 16] {
+        [
+            ShellKind::Zsh,
+            ShellKind::Fish,
+            ShellKind::Bash,
+            ShellKind::Nushell,
+            ShellKind::Xonsh,
+            ShellKind::Elvish,
+            ShellKind::PowerShell,
+            ShellKind::Tcsh,
+            ShellKind::Sh,
+            ShellKind::Dash,
+            ShellKind::Ksh,
+            ShellKind::Mksh,
+            ShellKind::Oils(OilsShell::Osh),
+            ShellKind::Oils(OilsShell::Ysh),
+            ShellKind::Murex,
+            ShellKind::Ion,
+        ]
+    }
+
+    /// List the known shells which `supported` returns `true` for, like "`zsh`, `fish`, and
+    /// `bash`" (with `conjunction` being `and`), for error messages.
+    pub fn list(conjunction: &str, supported: impl Fn(&ShellKind) -> bool) -> String {
+        let names = Self::known()
+            .iter()
+            .filter(|kind| supported(kind))
+            .map(|kind| format!("`{kind}`"))
+            .collect::<Vec<_>>();
+        match names.as_slice() {
+            [] => String::new(),
+            [name] => name.clone(),
+            [names @ .., last] => format!("{}, {conjunction} {last}", names.join(", ")),
+        }
+    }
+
+    /// Get the kind of shell from an executable name, like `fish` or `zsh-5.9`.
+    ///
+    /// Nix wrapper names (like `.fish-wrapped`), version suffixes (like `bash5.2`), and the
+    /// leading `-` of login shells are ignored; the rest must match a known shell exactly.
+    pub fn from_name(name: &str) -> Self {
+        match normalize_name(name) {
+            "zsh" => ShellKind::Zsh,
+            "fish" => ShellKind::Fish,
+            "bash" => ShellKind::Bash,
+            "nu" => ShellKind::Nushell,
+            "xonsh" => ShellKind::Xonsh,
+            "elvish" => ShellKind::Elvish,
+            "pwsh" | "powershell" => ShellKind::PowerShell,
+            "tcsh" | "csh" => ShellKind::Tcsh,
+            "sh" => ShellKind::Sh,
+            "dash" => ShellKind::Dash,
+            "ksh" => ShellKind::Ksh,
+            "mksh" | "lksh" => ShellKind::Mksh,
+            "osh" => ShellKind::Oils(OilsShell::Osh),
+            "ysh" => ShellKind::Oils(OilsShell::Ysh),
+            "murex" => ShellKind::Murex,
+            "ion" => ShellKind::Ion,
+            _ => ShellKind::Other(name.to_owned()),
+        }
+    }
+}
+
+impl FromStr for ShellKind {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match Self::from_name(name) {
+            ShellKind::Other(_) => Err(format!(
+                "unknown shell; expected one of {}",
+                Self::list("or", |_| true)
+            )),
+            kind => Ok(kind),
+        }
+    }
+}
+
+/// Strip the parts of an executable name which don't identify the shell: the leading `-` of login
+/// shells, Nix wrapper prefixes and suffixes (`.fish-wrapped`), and version suffixes (`zsh-5.9`,
+/// `bash5.2`, `ksh93`).
+fn normalize_name(name: &str) -> &str {
+    let name = strip_nix_wrapper(name.trim_start_matches('-'));
+    let unversioned = name
+        .trim_end_matches(|c: char| c.is_ascii_digit() || c == '.')
+        .trim_end_matches('-');
+    if unversioned.is_empty() {
+        name
+    } else {
+        unversioned
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Shell {
     pub kind: ShellKind,
@@ -51,6 +209,12 @@ pub struct Shell {
 }
 
 impl Shell {
+    /// Get a shell from an executable name or path, like `fish` or `/opt/homebrew/bin/fish`.
+    ///
+    /// If the name isn't a known shell, symlinks are resolved (looking up bare names on the
+    /// `$PATH`) and the name of the resolved executable is checked instead. Nix-wrapped
+    /// executables (like `.fish-wrapped`) are replaced with their wrappers when possible, so that
+    /// the wrapper's environment is set.
     pub fn from_path(path: impl AsRef<Utf8Path>) -> miette::Result<Self> {
         let path = path.as_ref();
         let file_name = match path.file_name() {
@@ -60,25 +224,204 @@ impl Shell {
             }
         };
 
-        let kind = if file_name.starts_with("zsh") {
-            ShellKind::Zsh
-        } else if file_name.starts_with("fish") {
-            ShellKind::Fish
-        } else if file_name.starts_with("bash") {
-            ShellKind::Bash
-        } else if file_name.starts_with("nu") {
-            ShellKind::Nushell
-        } else if file_name.starts_with("xonsh") {
-            ShellKind::Xonsh
-        } else {
-            ShellKind::Other(file_name.to_string())
-        };
+        let mut kind = ShellKind::from_name(file_name);
+        if let ShellKind::Other(_) = kind {
+            if let Some(resolved) = resolve(path) {
+                tracing::trace!(%path, %resolved, "Resolved symlinks to shell");
+                if let Some(resolved_kind) = resolved
+                    .file_name()
+                    .map(ShellKind::from_name)
+                    .filter(|kind| !matches!(kind, ShellKind::Other(_)))
+                {
+                    kind = resolved_kind;
+                }
+            }
+        }
 
         Ok(Self {
             kind,
-            path: path.to_owned(),
+            path: unwrap_nix_wrapper(path),
         })
     }
+
+    /// Get a shell from an executable name or path, with the kind of shell given explicitly.
+    ///
+    /// This is useful for shells with custom names which can't be detected. Like
+    /// [`Shell::from_path`], Nix-wrapped executables are replaced with their wrappers.
+    pub fn with_kind(path: impl AsRef<Utf8Path>, kind: ShellKind) -> Self {
+        Self {
+            kind,
+            path: unwrap_nix_wrapper(path.as_ref()),
+        }
+    }
+
+    /// Detect the user's shell from the environment.
+    ///
+    /// This checks the parent process (through `/proc/<ppid>/exe` and then
+    /// `/proc/<ppid>/comm`, on Linux), then `$SHELL`, then the user's passwd entry, and returns
+    /// the first known shell found.
+    ///
+    /// If the kind of shell is given, the first shell found is used whatever its name is, so that
+    /// shells with custom names can be detected.
+    pub fn detect(kind: Option<&ShellKind>) -> miette::Result<Self> {
+        let candidates: [(&str, FindShell); 4] = [
+            ("parent process executable", parent_exe),
+            ("parent process name", parent_comm),
+            ("$SHELL", || std::env::var("SHELL").ok()),
+            ("passwd entry", passwd_shell),
+        ];
+
+        for (reason, candidate) in candidates {
+            let path = match candidate() {
+                Some(path) if !path.is_empty() => path,
+                _ => {
+                    tracing::trace!(reason, "No shell found");
+                    continue;
+                }
+            };
+            let shell = match kind {
+                Some(kind) => Self::with_kind(&path, kind.clone()),
+                None => match Self::from_path(&path) {
+                    Ok(shell) => shell,
+                    Err(err) => {
+                        tracing::debug!(reason, %path, "Unable to use shell: {err}");
+                        continue;
+                    }
+                },
+            };
+            if let ShellKind::Other(_) = shell.kind {
+                tracing::trace!(reason, %path, "Not a known shell");
+                continue;
+            }
+            tracing::debug!(%shell, %path, reason, "Detected shell from the environment");
+            return Ok(shell);
+        }
+
+        Err(miette!(
+            "No shell given, and no shell could be detected from the environment\n\
+            Note: Give a shell like `fish`, or set `shell` in the configuration file"
+        ))
+    }
+}
+
+/// Resolve symlinks in the path to an executable, looking it up on the `$PATH` if it's a bare
+/// name like `fish`.
+fn resolve(path: &Utf8Path) -> Option<Utf8PathBuf> {
+    let path = if path.as_str().contains('/') {
+        path.to_owned()
+    } else {
+        crate::find_executable(path.as_str())?
+    };
+    path.canonicalize_utf8().ok()
+}
+
+/// Replace a Nix-wrapped executable with its wrapper, if it has one; see [`nix_wrapper`].
+fn unwrap_nix_wrapper(path: &Utf8Path) -> Utf8PathBuf {
+    match nix_wrapper(path) {
+        Some(wrapper) => {
+            tracing::trace!(%path, %wrapper, "Using Nix wrapper for shell");
+            wrapper
+        }
+        None => path.to_owned(),
+    }
+}
+
+/// If `path` is a Nix-wrapped executable like `/nix/store/...-fish/bin/.fish-wrapped`, get the
+/// wrapper (`/nix/store/...-fish/bin/fish`) if it exists.
+fn nix_wrapper(path: &Utf8Path) -> Option<Utf8PathBuf> {
+    let file_name = path.file_name()?;
+    let name = strip_nix_wrapper(file_name);
+    if name == file_name {
+        return None;
+    }
+    let wrapper = path.with_file_name(name);
+    wrapper.is_file().then_some(wrapper)
+}
+
+/// Strip the prefix and suffix Nix's `wrapProgram` adds to wrapped executables, like
+/// `.fish-wrapped`. Executables wrapped more than once are unwrapped fully.
+fn strip_nix_wrapper(mut name: &str) -> &str {
+    while let Some(unwrapped) = name
+        .strip_prefix('.')
+        .and_then(|name| name.strip_suffix("-wrapped"))
+    {
+        name = unwrapped;
+    }
+    name
+}
+
+/// A way to find the user's shell, returning a shell name or path.
+type FindShell = fn() -> Option<String>;
+
+/// Get the path of the parent process's executable from `/proc/<ppid>/exe`.
+#[cfg(target_os = "linux")]
+fn parent_exe() -> Option<String> {
+    let ppid = std::os::unix::process::parent_id();
+    std::fs::read_link(format!("/proc/{ppid}/exe"))
+        .ok()?
+        .into_os_string()
+        .into_string()
+        .ok()
+}
+
+/// Get the name of the parent process from `/proc/<ppid>/comm`.
+///
+/// This is readable even when `/proc/<ppid>/exe` isn't, like when the parent process belongs to
+/// another user.
+#[cfg(target_os = "linux")]
+fn parent_comm() -> Option<String> {
+    let ppid = std::os::unix::process::parent_id();
+    let comm = std::fs::read_to_string(format!("/proc/{ppid}/comm")).ok()?;
+    Some(comm.trim_end().to_owned())
+}
+
+/// Other platforms don't have `/proc`, so the parent process isn't checked.
+#[cfg(not(target_os = "linux"))]
+fn parent_exe() -> Option<String> {
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+fn parent_comm() -> Option<String> {
+    None
+}
+
+/// The largest buffer to try when reading the user's passwd entry.
+const PASSWD_BUFFER_LIMIT: usize = 1 << 20;
+
+/// Get the current user's login shell from their passwd entry, found by their real user ID.
+fn passwd_shell() -> Option<String> {
+    // SAFETY: `getuid` is always successful.
+    let uid = unsafe { libc::getuid() };
+    let mut buffer = vec![0 as libc::c_char; 1024];
+    // SAFETY: `passwd` is plain data; `getpwuid_r` fills it in.
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    loop {
+        // SAFETY: The pointers are valid, and `buffer.len()` is the buffer's real length.
+        let err = unsafe {
+            libc::getpwuid_r(
+                uid,
+                &mut passwd,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            )
+        };
+        match err {
+            0 => break,
+            libc::ERANGE if buffer.len() < PASSWD_BUFFER_LIMIT => {
+                buffer.resize(buffer.len() * 2, 0);
+            }
+            _ => return None,
+        }
+    }
+    if result.is_null() || passwd.pw_shell.is_null() {
+        return None;
+    }
+    // SAFETY: `pw_shell` points to a nul-terminated string in `buffer`, which is still alive.
+    let shell = unsafe { std::ffi::CStr::from_ptr(passwd.pw_shell) };
+    shell.to_str().ok().map(ToOwned::to_owned)
 }
 
 impl Display for Shell {
@@ -86,3 +429,70 @@ impl Display for Shell {
         write!(f, "{}", self.kind)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::TempDir;
+
+    #[test]
+    fn test_list() {
+        assert_eq!(
+            ShellKind::list("and", |kind| matches!(
+                kind,
+                ShellKind::Zsh | ShellKind::Fish | ShellKind::Nushell
+            )),
+            "`zsh`, `fish`, and `nu`"
+        );
+        assert_eq!(
+            ShellKind::list("or", |kind| matches!(kind, ShellKind::Fish)),
+            "`fish`"
+        );
+        assert_eq!(ShellKind::list("and", |_| false), "");
+    }
+
+    #[test]
+    fn test_known() {
+        for kind in ShellKind::known() {
+            assert!(!matches!(kind, ShellKind::Other(_)));
+            assert_eq!(
+                ShellKind::from_name(&kind.to_string()).to_string(),
+                kind.to_string()
+            );
+        }
+    }
+
+    #[test]
+    fn test_from_name() {
+        for (name, kind) in [
+            ("fish", ShellKind::Fish),
+            ("-zsh", ShellKind::Zsh),
+            ("zsh-5.9", ShellKind::Zsh),
+            ("bash5.2", ShellKind::Bash),
+            ("ksh93", ShellKind::Ksh),
+            (".fish-wrapped", ShellKind::Fish),
+            ("..nu-wrapped-wrapped", ShellKind::Nushell),
+            ("pwsh", ShellKind::PowerShell),
+        ] {
+            assert_eq!(ShellKind::from_name(name).to_string(), kind.to_string());
+        }
+    }
+
+    #[test]
+    fn test_from_name_unknown() {
+        for name in ["numbat", "bashtop", "fishy", "my-fish", ".fish", "5.2"] {
+            assert!(
+                matches!(ShellKind::from_name(name), ShellKind::Other(_)),
+                "{name} shouldn't be a known shell"
+            );
+        }
+    }
+
+    #[test]
+    fn test_with_kind_nix_wrapper() {
+        let dir = TempDir::new();
+        let wrapper = dir.write("my-fish", "");
+        let shell = Shell::with_kind(dir.path().join(".my-fish-wrapped"), ShellKind::Fish);
+        assert_eq!(shell.path, wrapper);
+    }
+}
@@ -0,0 +1,404 @@
+use camino::Utf8Path;
+use miette::miette;
+use miette::Context;
+use miette::IntoDiagnostic;
+use miette::NamedSource;
+use miette::SourceSpan;
+use minijinja::AutoEscape;
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::dirs;
+use crate::quote;
+use crate::shell::OilsShell;
+use crate::shell::ShellKind;
+
+/// The name of the directory in the configuration directory holding the user's templates.
+const TEMPLATES_DIR: &str = "templates";
+
+/// A template for shell environment code.
+#[derive(Debug)]
+pub struct Template {
+    /// The template's path, or its file name in `data/` for built-in templates.
+    name: String,
+    source: String,
+    /// The shell the template is for, which determines how the `quote` filter quotes strings.
+    kind: ShellKind,
+}
+
+/// An error compiling or rendering a template.
+#[derive(Debug, thiserror::Error, miette::Diagnostic)]
+#[error("Failed to render template {name}")]
+pub struct TemplateError {
+    name: String,
+    #[source_code]
+    source_code: NamedSource<String>,
+    #[label("{detail}")]
+    span: Option<SourceSpan>,
+    detail: String,
+}
+
+impl Template {
+    /// Find the template for the given shell.
+    ///
+    /// In order of precedence, this is:
+    /// 1. The `path` given on the command line with `--template`.
+    /// 2. The template for the shell in the configuration file's `[templates]`.
+    /// 3. `$XDG_CONFIG_HOME/nix-your-shell/templates/{shell}.j2`, like `fish.j2` or `nu.j2`.
+    /// 4. The built-in template for the shell.
+    pub fn find(
+        path: Option<&Utf8Path>,
+        config: &Config,
+        kind: &ShellKind,
+    ) -> miette::Result<Self> {
+        if let Some(path) = path {
+            return Self::from_path(path, kind);
+        }
+        if let Some(path) = config.templates.get(&kind.to_string()) {
+            return Self::from_path(&dirs::config_dir()?.join(path), kind);
+        }
+        let path = dirs::config_dir()?
+            .join(TEMPLATES_DIR)
+            .join(format!("{kind}.j2"));
+        if path.exists() {
+            return Self::from_path(&path, kind);
+        }
+        Self::builtin(kind)
+    }
+
+    /// Read a template for the given shell from a file.
+    pub fn from_path(path: &Utf8Path, kind: &ShellKind) -> miette::Result<Self> {
+        tracing::debug!(%path, "Using template");
+        let source = std::fs::read_to_string(path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to read template {path}"))?;
+        Ok(Self {
+            name: path.to_string(),
+            source,
+            kind: kind.clone(),
+        })
+    }
+
+    /// The built-in template for the given shell.
+    pub fn builtin(kind: &ShellKind) -> miette::Result<Self> {
+        let Some((name, source)) = builtin_source(kind) else {
+            return Err(miette!(
+                "I don't know how to generate a shell environment for `{kind}`\n\
+                Note: Supported shells are: {}\n\
+                Note: Use your own template with `--template`",
+                ShellKind::list("and", |kind| builtin_source(kind).is_some())
+            ));
+        };
+        Ok(Self {
+            name: name.to_owned(),
+            source: source.to_owned(),
+            kind: kind.clone(),
+        })
+    }
+
+    /// Render the template with the given context.
+    ///
+    /// Templates can quote strings for the shell with the `quote` filter, like
+    /// `{{ executable | quote }}`.
+    pub fn render(&self, context: impl Serialize) -> miette::Result<String> {
+        let mut env = minijinja::Environment::new();
+        env.set_debug(true);
+        env.set_auto_escape_callback(|_| AutoEscape::None);
+        let kind = self.kind.clone();
+        env.add_filter("quote", move |value: String| {
+            quote::word(&kind, &value).ok_or_else(|| {
+                minijinja::Error::new(
+                    minijinja::ErrorKind::InvalidOperation,
+                    format!("I don't know how to quote strings for `{kind}`"),
+                )
+            })
+        });
+        env.render_named_str(&self.name, &self.source, context)
+            .map_err(|err| self.error(err).into())
+    }
+
+    fn error(&self, err: minijinja::Error) -> TemplateError {
+        // Fall back to highlighting the whole line if there's no more precise location.
+        let range = err.range().or_else(|| {
+            let line = err.line()?;
+            let start: usize = self
+                .source
+                .split_inclusive('\n')
+                .take(line - 1)
+                .map(str::len)
+                .sum();
+            let len = self.source[start..].lines().next().map_or(0, str::len);
+            Some(start..start + len)
+        });
+        TemplateError {
+            name: self.name.clone(),
+            source_code: NamedSource::new(&self.name, self.source.clone()),
+            span: range.map(SourceSpan::from),
+            detail: err
+                .detail()
+                .map(ToOwned::to_owned)
+                .unwrap_or_else(|| err.kind().to_string()),
+        }
+    }
+}
+
+/// The file name and source of the built-in template for the given shell, if any.
+fn builtin_source(kind: &ShellKind) -> Option<(&'static str, &'static str)> {
+    macro_rules! builtin {
+        ($name:literal) => {
+            ($name, include_str!(concat!("../data/", $name)))
+        };
+    }
+
+    let builtin = match kind {
+        ShellKind::Zsh | ShellKind::Bash => {
+            builtin!("env.sh.j2")
+        }
+
+        ShellKind::Fish => {
+            builtin!("env.fish.j2")
+        }
+
+        ShellKind::Nushell => {
+            builtin!("env.nu.j2")
+        }
+
+        ShellKind::Xonsh => {
+            builtin!("env.xsh.j2")
+        }
+
+        ShellKind::Elvish => {
+            builtin!("env.elv.j2")
+        }
+
+        ShellKind::PowerShell => {
+            builtin!("env.ps1.j2")
+        }
+
+        ShellKind::Tcsh => {
+            builtin!("env.csh.j2")
+        }
+
+        ShellKind::Sh
+        | ShellKind::Dash
+        | ShellKind::Ksh
+        | ShellKind::Mksh
+        | ShellKind::Oils(OilsShell::Osh) => {
+            builtin!("env.posix.j2")
+        }
+
+        ShellKind::Oils(OilsShell::Ysh) => {
+            builtin!("env.ysh.j2")
+        }
+
+        ShellKind::Murex => {
+            builtin!("env.mx.j2")
+        }
+
+        ShellKind::Ion => {
+            builtin!("env.ion.j2")
+        }
+
+        ShellKind::Other(_) => return None,
+    };
+    Some(builtin)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(kind: ShellKind, executable: &str, shell: &str) -> String {
+        Template::builtin(&kind)
+            .unwrap()
+            .render(minijinja::context! {
+                executable => executable,
+                extra_args => ["--nom=auto", "--backend", "cached-nix-shell"],
+                shell => shell,
+                shell_kind => kind.to_string(),
+                version => env!("CARGO_PKG_VERSION"),
+                nom => true,
+                native => false,
+                nix_sourced_var => "__NIX_YOUR_SHELL_SOURCED",
+                commands => [minijinja::context! {
+                    name => "devenv",
+                    description => "Developer environments",
+                }],
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_quote_elvish() {
+        let rendered = render(
+            ShellKind::Elvish,
+            "/home/zoë/bin/nix-your-shell",
+            "/opt/it's \"my\"\nshell",
+        );
+        // Elvish single-quoted strings are verbatim, except that `''` is a literal `'`.
+        assert!(rendered.contains(
+            "fn nix-shell {|@args|\n    \
+            '/home/zoë/bin/nix-your-shell' '--nom=auto' --backend cached-nix-shell \
+            '/opt/it''s \"my\"\nshell' nix-shell -- $@args\n}"
+        ));
+        assert!(rendered.contains(
+            "fn devenv {|@args|\n    \
+            '/home/zoë/bin/nix-your-shell' '--nom=auto' --backend cached-nix-shell \
+            '/opt/it''s \"my\"\nshell' devenv -- $@args\n}"
+        ));
+        assert!(rendered
+            .contains("edit:add-vars [&nix-shell~=$nix-shell~ &nix~=$nix~ &devenv~=$devenv~]"));
+    }
+
+    #[test]
+    fn test_quote_bare() {
+        let rendered = render(ShellKind::Bash, "nix-your-shell", "/bin/bash");
+        assert!(rendered.contains(
+            "    nix-your-shell '--nom=auto' --backend cached-nix-shell /bin/bash nix -- \"$@\"\n"
+        ));
+    }
+
+    #[test]
+    fn test_render_posix() {
+        let expected = "    /home/zoë/nix-your-shell '--nom=auto' --backend cached-nix-shell \
+            '/opt/it'\\''s \"my\"\nshell' devenv -- \"$@\"\n}";
+        for kind in [ShellKind::Bash, ShellKind::Zsh, ShellKind::Dash] {
+            let rendered = render(kind, "/home/zoë/nix-your-shell", "/opt/it's \"my\"\nshell");
+            assert!(rendered.contains(expected), "{rendered}");
+        }
+    }
+
+    #[test]
+    fn test_render_fish() {
+        let rendered = render(ShellKind::Fish, "/home/zoë/nix-your-shell", "/tmp/my fish");
+        assert!(rendered.contains(
+            "    '/home/zoë/nix-your-shell' '--nom=auto' --backend cached-nix-shell '/tmp/my fish' \
+            devenv -- $argv\n"
+        ));
+    }
+
+    #[test]
+    fn test_render_tcsh() {
+        let rendered = render(ShellKind::Tcsh, "/home/zoë/nix-your-shell", "/bin/tcsh");
+        // The alias is quoted once when it's defined and again when it's used.
+        assert!(rendered.contains(
+            "alias nix ''\\''/home/zoë/nix-your-shell'\\'' '\\''--nom=auto'\\'' --backend \
+            cached-nix-shell /bin/tcsh nix -- \\!*'\n"
+        ));
+        assert!(rendered.ends_with(
+            "alias devenv ''\\''/home/zoë/nix-your-shell'\\'' '\\''--nom=auto'\\'' --backend \
+            cached-nix-shell /bin/tcsh devenv -- \\!*'"
+        ));
+    }
+
+    #[test]
+    fn test_render_ysh() {
+        let rendered = render(
+            ShellKind::Oils(OilsShell::Ysh),
+            "/home/zoë/nix-your-shell",
+            "/opt/it's\nysh",
+        );
+        assert!(rendered.contains(
+            "u'/home/zoë/nix-your-shell' u'--nom=auto' --backend cached-nix-shell \
+            u'/opt/it\\'s\\u{a}ysh' devenv"
+        ));
+    }
+
+    #[test]
+    fn test_render_murex() {
+        let rendered = render(ShellKind::Murex, "/home/zoë/nix-your-shell", "/bin/murex");
+        assert!(rendered.contains(
+            "'/home/zoë/nix-your-shell' '--nom=auto' --backend cached-nix-shell /bin/murex devenv"
+        ));
+    }
+
+    #[test]
+    fn test_render_xonsh() {
+        let rendered = render(
+            ShellKind::Xonsh,
+            "/home/zoë/my bin/nix-your-shell",
+            "/tmp/my-xonsh",
+        );
+        // The alias is quoted once as a Python string and again as `xonsh` code.
+        assert!(rendered.contains(
+            "aliases['devenv'] = \"\\\"/home/zoë/my bin/nix-your-shell\\\" \\\"--nom=auto\\\" \
+            --backend cached-nix-shell /tmp/my-xonsh devenv -- @($args)\""
+        ));
+    }
+
+    #[test]
+    fn test_render_ion() {
+        let rendered = render(
+            ShellKind::Ion,
+            "/home/zoë/my $bin/nix-your-shell",
+            "/bin/ion",
+        );
+        assert!(rendered.ends_with(
+            "alias devenv=\"'/home/zoë/my \\$bin/nix-your-shell' '--nom=auto' --backend \
+            cached-nix-shell /bin/ion devenv --\""
+        ));
+    }
+
+    #[test]
+    fn test_render_nu() {
+        let rendered = render(
+            ShellKind::Nushell,
+            "/home/zoë/my bin/nix-your-shell",
+            "/tmp/my nu",
+        );
+        assert!(rendered.contains("which r#'/home/zoë/my bin/nix-your-shell'# | is-empty"));
+        assert!(rendered.contains(
+            "let args = [\"--nom=auto\",\"--backend\",\"cached-nix-shell\"] \
+            ++ [r#'/tmp/my nu'#, $command, \"--\"] ++ $args\n    \
+            run-external r#'/home/zoë/my bin/nix-your-shell'# ...$args"
+        ));
+    }
+
+    #[test]
+    fn test_render_powershell() {
+        let rendered = render(
+            ShellKind::PowerShell,
+            "/home/o'brien/my bin/nix-your-shell",
+            "/opt/my pwsh",
+        );
+        assert!(rendered.contains(
+            "    & '/home/o''brien/my bin/nix-your-shell' '--nom=auto' --backend cached-nix-shell \
+            '/opt/my pwsh' devenv '--' @args\n"
+        ));
+    }
+
+    #[test]
+    fn test_render_unquotable() {
+        let template = Template::builtin(&ShellKind::Murex).unwrap();
+        let err = template
+            .render(minijinja::context! {
+                executable => "/home/o'brien/nix-your-shell",
+                extra_args => Vec::<String>::new(),
+                shell => "murex",
+                commands => Vec::<String>::new(),
+            })
+            .unwrap_err();
+        assert!(format!("{err:?}").contains("I don't know how to quote strings for `murex`"));
+    }
+
+    #[test]
+    fn test_render_other_templates() {
+        for kind in [
+            ShellKind::Nushell,
+            ShellKind::Xonsh,
+            ShellKind::Ion,
+            ShellKind::Oils(OilsShell::Osh),
+        ] {
+            let rendered = render(kind.clone(), "/bin/nix-your-shell", "/bin/shell");
+            assert!(
+                rendered.contains("devenv"),
+                "{kind} template doesn't wrap `devenv`: {rendered}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_builtin_unsupported() {
+        assert!(Template::builtin(&ShellKind::Other("rc".to_owned())).is_err());
+    }
+}
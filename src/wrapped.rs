@@ -0,0 +1,317 @@
+use miette::miette;
+use serde::Serialize;
+
+use crate::nix;
+use crate::nix::NixShellBackend;
+use crate::nix_cli::NixCli;
+use crate::prompt::ShellInfo;
+
+/// A command besides `nix` and `nix-shell` which `nix-your-shell` can wrap to run the shell
+/// instead of `bash`.
+#[derive(Debug, Serialize)]
+pub struct WrappedCommand {
+    /// The name of the command, like `devenv`.
+    pub name: &'static str,
+    /// A short description of the command, for shells which support them.
+    pub description: &'static str,
+    /// How to make the command run the shell.
+    #[serde(skip)]
+    injection: Injection,
+}
+
+/// How to make a wrapped command run the shell.
+#[derive(Debug, Clone, Copy)]
+enum Injection {
+    /// Add `--command <shell>` like `nix-shell`, but only if `--run-env` is given.
+    NixBuildRunEnv,
+    /// Add the shell as the command to run for `devenv shell`.
+    DevenvShell,
+    /// Wrap the `nix` or `nix-shell` command run with `nix-portable`.
+    NixPortable,
+}
+
+/// The commands `nix-your-shell` knows how to wrap, besides `nix` and `nix-shell`.
+pub const WRAPPED_COMMANDS: &[WrappedCommand] = &[
+    WrappedCommand {
+        name: "nix-build",
+        description: "Build a Nix expression, or start a shell with --run-env",
+        injection: Injection::NixBuildRunEnv,
+    },
+    WrappedCommand {
+        name: "devenv",
+        description: "Fast, Declarative, Reproducible, and Composable Developer Environments",
+        injection: Injection::DevenvShell,
+    },
+    WrappedCommand {
+        name: "nix-portable",
+        description: "Use Nix without installing it",
+        injection: Injection::NixPortable,
+    },
+];
+
+/// `devenv` flags which take arguments.
+///
+/// `-c`/`--clean` takes an optional list of environment variables, and is handled separately.
+///
+/// See: <https://devenv.sh/reference/options/>
+const DEVENV_FLAG_ARITIES: &[(&str, usize)] = &[
+    ("-j", 1),
+    ("--max-jobs", 1),
+    ("-u", 1),
+    ("--cores", 1),
+    ("-s", 1),
+    ("--system", 1),
+    ("--log-format", 1),
+    ("-O", 2),
+    ("--option", 2),
+    ("-n", 2),
+    ("--nix-option", 2),
+    ("-o", 2),
+    ("--override-input", 2),
+];
+
+/// Arguments to a wrapped command.
+#[derive(Debug)]
+pub struct WrappedArgs {
+    /// The program to run, like `devenv` or `nix-portable`.
+    pub program: &'static str,
+    /// Arguments to the program.
+    pub args: Vec<String>,
+    /// Information about the shell, if the command will run the shell.
+    pub shell_info: Option<ShellInfo>,
+}
+
+impl WrappedCommand {
+    /// Get a wrapped command by name, if we know how to wrap it.
+    pub fn get(name: &str) -> Option<&'static Self> {
+        WRAPPED_COMMANDS.iter().find(|command| command.name == name)
+    }
+
+    /// Find a wrapped command by name, for `--wrap-command`.
+    pub fn find(name: &str) -> miette::Result<&'static Self> {
+        Self::get(name).ok_or_else(|| {
+            if name == "cached-nix-shell" {
+                return miette!(
+                    "`cached-nix-shell` can't be wrapped\n\
+                    Note: Use `--backend cached-nix-shell` to run `nix-shell` commands with it"
+                );
+            }
+            miette!(
+                "I don't know how to wrap `{name}`\n\
+                Note: Commands which can be wrapped are: {}",
+                Self::names()
+            )
+        })
+    }
+
+    /// The names of the commands which can be wrapped, like "`nix-build`, `devenv`", for error
+    /// messages.
+    pub fn names() -> String {
+        WRAPPED_COMMANDS
+            .iter()
+            .map(|command| format!("`{}`", command.name))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Transform arguments to this command to run the specified `command`.
+    pub fn transform(
+        &self,
+        args: Vec<String>,
+        command: &str,
+        cli: &NixCli,
+    ) -> miette::Result<WrappedArgs> {
+        let (args, shell_info) = match self.injection {
+            Injection::NixBuildRunEnv => {
+                let run_env = args
+                    .iter()
+                    .take_while(|arg| *arg != "--")
+                    .any(|arg| arg == "--run-env");
+                if run_env {
+                    // `nix-build` only recognizes `--command` after `--run-env`.
+                    let args = args.into_iter().filter(|arg| arg != "--run-env").collect();
+                    let (new_args, shell_info) = nix_shell("nix-build", args, command)?;
+                    (prepend("--run-env", new_args), shell_info)
+                } else {
+                    (args, None)
+                }
+            }
+
+            Injection::DevenvShell => devenv(args, command),
+
+            Injection::NixPortable => match args.first().map(|arg| arg.as_str()) {
+                Some("nix") => {
+                    let new_args = nix::transform_nix(args[1..].to_vec(), command, cli)?;
+                    let shell_info = new_args.command_index.map(|_| {
+                        ShellInfo::from_env().enter(
+                            &new_args.subcommand_path.join(" "),
+                            &[],
+                            &new_args.installables,
+                        )
+                    });
+                    (prepend("nix", new_args.args), shell_info)
+                }
+                Some("nix-shell") => {
+                    let (new_args, shell_info) =
+                        nix_shell("nix-shell", args[1..].to_vec(), command)?;
+                    (prepend("nix-shell", new_args), shell_info)
+                }
+                _ => (args, None),
+            },
+        };
+        Ok(WrappedArgs {
+            program: self.name,
+            args,
+            shell_info,
+        })
+    }
+}
+
+/// Transform arguments to a `nix-shell` invocation run by another command.
+fn nix_shell(
+    name: &str,
+    args: Vec<String>,
+    command: &str,
+) -> miette::Result<(Vec<String>, Option<ShellInfo>)> {
+    let new_args = nix::transform_nix_shell(args, command, NixShellBackend::NixShell)?;
+    let shell_info = new_args
+        .command_index
+        .map(|_| ShellInfo::from_env().enter(name, &new_args.packages, &new_args.paths));
+    Ok((new_args.args, shell_info))
+}
+
+/// Transform arguments to `devenv` to run the `command` in `devenv shell`.
+///
+/// The arguments are unchanged unless the subcommand is `shell` and no command to run is given.
+fn devenv(args: Vec<String>, command: &str) -> (Vec<String>, Option<ShellInfo>) {
+    let mut positional = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        let arg = args[i].as_str();
+        if arg == "--" {
+            positional.extend(&args[i + 1..]);
+            break;
+        } else if arg.starts_with('-') {
+            if matches!(arg, "-h" | "--help" | "-V" | "--version") {
+                return (args, None);
+            }
+            if matches!(arg, "-c" | "--clean") {
+                // `--clean` keeps the given environment variables, like `--clean HOME,USER` or
+                // `--clean HOME USER`.
+                while args.get(i + 1).is_some_and(|arg| is_env_var_list(arg)) {
+                    i += 1;
+                }
+            } else if !arg.contains('=') {
+                // `--flag=value` includes its argument.
+                i += DEVENV_FLAG_ARITIES
+                    .iter()
+                    .find(|(flag, _)| *flag == arg)
+                    .map(|(_, arity)| arity)
+                    .unwrap_or(&0);
+            }
+        } else {
+            positional.push(&args[i]);
+        }
+        i += 1;
+    }
+
+    if positional.len() != 1 || positional[0] != "shell" {
+        return (args, None);
+    }
+
+    let shell_info = ShellInfo::from_env().enter("devenv shell", &[], &[]);
+    let mut args = args;
+    args.push(command.to_owned());
+    (args, Some(shell_info))
+}
+
+/// Does `arg` look like a comma-separated list of environment variables, like `HOME,USER`?
+fn is_env_var_list(arg: &str) -> bool {
+    arg.split(',').all(|name| {
+        name.starts_with(|c: char| c.is_ascii_uppercase() || c == '_')
+            && name
+                .chars()
+                .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+    })
+}
+
+fn prepend(first: &str, rest: Vec<String>) -> Vec<String> {
+    std::iter::once(first.to_owned()).chain(rest).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prompt::SUBCOMMAND_VAR;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| (*arg).to_owned()).collect()
+    }
+
+    fn devenv_wraps(given: &[&str]) -> bool {
+        let (new_args, shell_info) = devenv(args(given), "fish");
+        if shell_info.is_some() {
+            assert_eq!(new_args.last().unwrap(), "fish");
+        }
+        shell_info.is_some()
+    }
+
+    #[test]
+    fn test_devenv() {
+        assert!(devenv_wraps(&["shell"]));
+        assert!(!devenv_wraps(&["shell", "ls"]));
+        assert!(!devenv_wraps(&["up"]));
+        assert!(!devenv_wraps(&["shell", "--help"]));
+    }
+
+    #[test]
+    fn test_devenv_flag_arguments() {
+        assert!(devenv_wraps(&["--nix-option", "cores", "4", "shell"]));
+        assert!(devenv_wraps(&["-n", "cores", "4", "shell"]));
+        assert!(devenv_wraps(&[
+            "-o",
+            "nixpkgs",
+            "github:NixOS/nixpkgs",
+            "shell"
+        ]));
+        assert!(devenv_wraps(&["--log-format", "cli", "shell"]));
+        assert!(devenv_wraps(&["--max-jobs=4", "shell"]));
+        assert!(devenv_wraps(&["--log-format=cli", "shell"]));
+    }
+
+    #[test]
+    fn test_devenv_clean() {
+        assert!(devenv_wraps(&["--clean", "shell"]));
+        assert!(devenv_wraps(&["-c", "HOME,USER", "shell"]));
+        assert!(devenv_wraps(&["--clean", "HOME", "USER", "shell"]));
+        assert!(devenv_wraps(&["--clean", "--impure", "shell"]));
+    }
+
+    #[test]
+    fn test_nix_build_run_env() {
+        let nix_build = WrappedCommand::get("nix-build").unwrap();
+        let cli = NixCli::snapshot().unwrap();
+        let new_args = nix_build
+            .transform(args(&["--run-env", "shell.nix"]), "fish", &cli)
+            .unwrap();
+        assert_eq!(
+            new_args.args,
+            args(&["--run-env", "--command", "fish", "shell.nix"])
+        );
+        let env_vars = new_args.shell_info.unwrap().env_vars();
+        assert!(env_vars.contains(&(SUBCOMMAND_VAR, "nix-build".to_owned())));
+
+        let new_args = nix_build
+            .transform(args(&["default.nix"]), "fish", &cli)
+            .unwrap();
+        assert_eq!(new_args.args, args(&["default.nix"]));
+        assert!(new_args.shell_info.is_none());
+    }
+
+    #[test]
+    fn test_get() {
+        assert!(WrappedCommand::get("devenv").is_some());
+        assert!(WrappedCommand::get("cached-nix-shell").is_none());
+        assert!(WrappedCommand::find("nix-dev").is_err());
+    }
+}